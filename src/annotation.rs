@@ -0,0 +1,528 @@
+use egui::{Color32, ColorImage, Context, FontId, Painter, Pos2, Rect, Stroke, Vec2};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Le annotazioni sono salvate in coordinate normalizzate (0..1) sul frame catturato e gli spessori
+// in frazioni della sua altezza: la stessa forma si disegna sull'anteprima e si compone nel video
+const PEN_WIDTH: f32 = 0.004;
+const HIGHLIGHTER_WIDTH: f32 = 0.025;
+const TEXT_SIZE: f32 = 0.04;
+// la maschera del testo si rasterizza una volta a questa dimensione e poi si scala sul frame
+const TEXT_MASK_POINTS: f32 = 48.0;
+const ARROW_HEAD_ANGLE: f32 = 0.45;
+pub const HIGHLIGHTER_COLOR: Color32 = Color32::from_rgba_premultiplied(90, 85, 0, 90);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tool {
+    Pen,
+    Arrow,
+    Rectangle,
+    Text,
+    Highlighter,
+}
+
+impl Tool {
+    pub const ALL: [Tool; 5] = [Tool::Pen, Tool::Arrow, Tool::Rectangle, Tool::Text, Tool::Highlighter];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Tool::Pen => "Pen",
+            Tool::Arrow => "Arrow",
+            Tool::Rectangle => "Rectangle",
+            Tool::Text => "Text",
+            Tool::Highlighter => "Highlighter",
+        }
+    }
+}
+
+/// Coverage of a rendered text, taken from the egui font atlas.
+#[derive(Debug, PartialEq)]
+pub struct TextMask {
+    width: usize,
+    height: usize,
+    coverage: Vec<f32>,
+}
+
+impl TextMask {
+    fn render(ctx: &Context, text: &str) -> TextMask {
+        ctx.fonts(|fonts| {
+            let galley = fonts.layout_no_wrap(text.to_string(), FontId::proportional(TEXT_MASK_POINTS), Color32::WHITE);
+            let atlas = fonts.image();
+            let ppp = fonts.pixels_per_point();
+            let width = (galley.size().x * ppp).ceil() as usize;
+            let height = (galley.size().y * ppp).ceil() as usize;
+            let mut coverage = vec![0.0; width * height];
+            for row in &galley.rows {
+                for glyph in &row.glyphs {
+                    let uv = glyph.uv_rect;
+                    if uv.is_nothing() {
+                        continue;
+                    }
+                    let left_top = ((glyph.pos.to_vec2() + uv.offset) * ppp).round();
+                    for ty in uv.min[1]..uv.max[1] {
+                        for tx in uv.min[0]..uv.max[0] {
+                            let x = left_top.x as i64 + (tx - uv.min[0]) as i64;
+                            let y = left_top.y as i64 + (ty - uv.min[1]) as i64;
+                            if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+                                continue;
+                            }
+                            let value = atlas.pixels[ty as usize * atlas.size[0] + tx as usize];
+                            let cell = &mut coverage[y as usize * width + x as usize];
+                            *cell = cell.max(value);
+                        }
+                    }
+                }
+            }
+            TextMask { width, height, coverage }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    Stroke { points: Vec<Pos2>, color: Color32, width: f32 },
+    Arrow { from: Pos2, to: Pos2, color: Color32, width: f32 },
+    Rectangle { from: Pos2, to: Pos2, color: Color32, width: f32 },
+    Text { pos: Pos2, text: String, color: Color32, size: f32, mask: Arc<TextMask> },
+}
+
+// punte della freccia, in pixel
+fn arrow_head(from: Pos2, to: Pos2, length: f32) -> [Pos2; 2] {
+    let dir = (from - to).normalized();
+    let rotate = |angle: f32| {
+        let (sin, cos) = angle.sin_cos();
+        to + Vec2::new(dir.x * cos - dir.y * sin, dir.x * sin + dir.y * cos) * length
+    };
+    [rotate(ARROW_HEAD_ANGLE), rotate(-ARROW_HEAD_ANGLE)]
+}
+
+impl Shape {
+    // segmenti che compongono la forma in pixel dell'area indicata, il testo non ne ha
+    fn segments(&self, rect: Rect) -> Vec<(Pos2, Pos2)> {
+        let px = |p: Pos2| rect.min + p.to_vec2() * rect.size();
+        match self {
+            Shape::Stroke { points, .. } => {
+                let points: Vec<Pos2> = points.iter().map(|p| px(*p)).collect();
+                if points.len() == 1 {
+                    return vec![(points[0], points[0])];
+                }
+                points.windows(2).map(|w| (w[0], w[1])).collect()
+            }
+            Shape::Arrow { from, to, width, .. } => {
+                let (from, to) = (px(*from), px(*to));
+                let head = (from.distance(to) / 4.0).max(width * rect.height() * 4.0);
+                let [left, right] = arrow_head(from, to, head);
+                vec![(from, to), (to, left), (to, right)]
+            }
+            Shape::Rectangle { from, to, .. } => {
+                let r = Rect::from_two_pos(px(*from), px(*to));
+                vec![
+                    (r.left_top(), r.right_top()),
+                    (r.right_top(), r.right_bottom()),
+                    (r.right_bottom(), r.left_bottom()),
+                    (r.left_bottom(), r.left_top()),
+                ]
+            }
+            Shape::Text { .. } => Vec::new(),
+        }
+    }
+
+    fn color(&self) -> Color32 {
+        match self {
+            Shape::Stroke { color, .. } | Shape::Arrow { color, .. } | Shape::Rectangle { color, .. } | Shape::Text { color, .. } => *color,
+        }
+    }
+
+    fn width(&self) -> f32 {
+        match self {
+            Shape::Stroke { width, .. } | Shape::Arrow { width, .. } | Shape::Rectangle { width, .. } => *width,
+            Shape::Text { .. } => 0.0,
+        }
+    }
+
+    /// Draws the shape on the preview, `rect` being where the frame is shown.
+    pub fn paint(&self, painter: &Painter, rect: Rect) {
+        if let Shape::Text { pos, text, color, size, .. } = self {
+            let pos = rect.min + pos.to_vec2() * rect.size();
+            painter.text(pos, egui::Align2::LEFT_TOP, text, FontId::proportional(size * rect.height()), *color);
+            return;
+        }
+        let stroke = Stroke::new((self.width() * rect.height()).max(1.0), self.color());
+        if let Shape::Stroke { points, .. } = self {
+            let points = points.iter().map(|p| rect.min + p.to_vec2() * rect.size()).collect();
+            painter.add(egui::Shape::line(points, stroke));
+            return;
+        }
+        for (a, b) in self.segments(rect) {
+            painter.line_segment([a, b], stroke);
+        }
+    }
+
+    /// Draws the shape into a captured frame.
+    pub fn composite(&self, image: &mut ColorImage) {
+        let rect = Rect::from_min_size(Pos2::ZERO, Vec2::new(image.size[0] as f32, image.size[1] as f32));
+        if let Shape::Text { pos, color, size, mask, .. } = self {
+            composite_mask(image, rect.min + pos.to_vec2() * rect.size(), size * rect.height(), mask, *color);
+            return;
+        }
+        let radius = (self.width() * rect.height() / 2.0).max(0.5);
+        // la copertura si calcola prima di fondere, così l'evidenziatore non si accumula dove i segmenti si sovrappongono
+        let segments = self.segments(rect);
+        let Some(bounds) = segments.iter()
+            .map(|(a, b)| Rect::from_two_pos(*a, *b))
+            .reduce(|acc, r| acc.union(r))
+            .map(|r| r.expand(radius + 1.0).intersect(rect))
+        else {
+            return;
+        };
+        if bounds.width() <= 0.0 || bounds.height() <= 0.0 {
+            return;
+        }
+        let (x0, y0) = (bounds.min.x.floor() as usize, bounds.min.y.floor() as usize);
+        let (x1, y1) = (bounds.max.x.ceil() as usize, bounds.max.y.ceil() as usize);
+        let (w, h) = (x1 - x0, y1 - y0);
+        let mut mask = vec![false; w * h];
+        for (a, b) in segments {
+            let steps = (a.distance(b) / (radius / 2.0).max(0.5)).ceil().max(1.0) as usize;
+            for i in 0..=steps {
+                let center = a.lerp(b, i as f32 / steps as f32);
+                let (cx0, cx1) = ((center.x - radius).floor().max(x0 as f32) as usize, (center.x + radius).ceil().min(x1 as f32) as usize);
+                let (cy0, cy1) = ((center.y - radius).floor().max(y0 as f32) as usize, (center.y + radius).ceil().min(y1 as f32) as usize);
+                for y in cy0..cy1 {
+                    for x in cx0..cx1 {
+                        if Pos2::new(x as f32 + 0.5, y as f32 + 0.5).distance_sq(center) <= radius * radius {
+                            mask[(y - y0) * w + (x - x0)] = true;
+                        }
+                    }
+                }
+            }
+        }
+        let color = self.color();
+        for y in 0..h {
+            for x in 0..w {
+                if mask[y * w + x] {
+                    blend(image, x0 + x, y0 + y, color, 1.0);
+                }
+            }
+        }
+    }
+}
+
+// fusione "over" con colori premoltiplicati, il frame catturato è opaco
+fn blend(image: &mut ColorImage, x: usize, y: usize, color: Color32, coverage: f32) {
+    let index = y * image.size[0] + x;
+    let Some(dst) = image.pixels.get_mut(index) else {
+        return;
+    };
+    let alpha = color.a() as f32 / 255.0 * coverage;
+    let mix = |s: u8, d: u8| (s as f32 * coverage + d as f32 * (1.0 - alpha)).round().min(255.0) as u8;
+    *dst = Color32::from_rgb(mix(color.r(), dst.r()), mix(color.g(), dst.g()), mix(color.b(), dst.b()));
+}
+
+fn composite_mask(image: &mut ColorImage, pos: Pos2, height: f32, mask: &TextMask, color: Color32) {
+    if mask.height == 0 || mask.width == 0 {
+        return;
+    }
+    let scale = height / mask.height as f32;
+    let width = (mask.width as f32 * scale).ceil() as usize;
+    let height = height.ceil() as usize;
+    let (left, top) = (pos.x.round() as i64, pos.y.round() as i64);
+    for y in 0..height {
+        for x in 0..width {
+            let (ix, iy) = (left + x as i64, top + y as i64);
+            if ix < 0 || iy < 0 || ix >= image.size[0] as i64 || iy >= image.size[1] as i64 {
+                continue;
+            }
+            let mx = ((x as f32 / scale) as usize).min(mask.width - 1);
+            let my = ((y as f32 / scale) as usize).min(mask.height - 1);
+            let coverage = mask.coverage[my * mask.width + mx];
+            if coverage > 0.0 {
+                blend(image, ix as usize, iy as usize, color, coverage);
+            }
+        }
+    }
+}
+
+/// Presenter annotations over the captured frame, cleared after `timeout` without changes.
+pub struct AnnotationLayer {
+    shapes: Vec<Shape>,
+    drawing: Option<Shape>,
+    last_change: Instant,
+    pub tool: Tool,
+    pub color: Color32,
+    pub text: String,
+    // 0 = le annotazioni restano finché non si cancellano
+    pub timeout_secs: u32,
+}
+
+impl Default for AnnotationLayer {
+    fn default() -> Self {
+        AnnotationLayer {
+            shapes: Vec::new(),
+            drawing: None,
+            last_change: Instant::now(),
+            tool: Tool::Pen,
+            color: Color32::RED,
+            text: String::new(),
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl AnnotationLayer {
+    pub const MAX_TIMEOUT_SECS: u32 = 300;
+
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty() && self.drawing.is_none()
+    }
+
+    pub fn undo(&mut self) {
+        self.shapes.pop();
+        self.last_change = Instant::now();
+    }
+
+    pub fn clear(&mut self) {
+        self.shapes.clear();
+        self.drawing = None;
+    }
+
+    /// Clears the layer once the timeout has passed since the last change.
+    pub fn expire(&mut self) {
+        if self.timeout_secs > 0
+            && self.drawing.is_none()
+            && self.last_change.elapsed() >= Duration::from_secs(self.timeout_secs as u64)
+        {
+            self.shapes.clear();
+        }
+    }
+
+    /// Pointer handling over the preview: `pos` is the pointer normalised on the frame.
+    pub fn pointer(&mut self, ctx: &Context, pos: Pos2, pressed: bool, down: bool, released: bool) {
+        let pos = pos.clamp(Pos2::ZERO, Pos2::new(1.0, 1.0));
+        // il timeout riparte solo quando una forma viene aggiunta o modificata, non a ogni movimento del mouse
+        let mut changed = false;
+        if pressed {
+            self.drawing = match self.tool {
+                Tool::Pen => Some(Shape::Stroke { points: vec![pos], color: self.color, width: PEN_WIDTH }),
+                Tool::Highlighter => Some(Shape::Stroke { points: vec![pos], color: HIGHLIGHTER_COLOR, width: HIGHLIGHTER_WIDTH }),
+                Tool::Arrow => Some(Shape::Arrow { from: pos, to: pos, color: self.color, width: PEN_WIDTH }),
+                Tool::Rectangle => Some(Shape::Rectangle { from: pos, to: pos, color: self.color, width: PEN_WIDTH }),
+                Tool::Text => {
+                    if !self.text.trim().is_empty() {
+                        let mask = Arc::new(TextMask::render(ctx, &self.text));
+                        self.shapes.push(Shape::Text { pos, text: self.text.clone(), color: self.color, size: TEXT_SIZE, mask });
+                        changed = true;
+                    }
+                    None
+                }
+            };
+            changed |= self.drawing.is_some();
+        } else if down {
+            match &mut self.drawing {
+                Some(Shape::Stroke { points, .. }) => {
+                    if points.last() != Some(&pos) {
+                        points.push(pos);
+                        changed = true;
+                    }
+                }
+                Some(Shape::Arrow { to, .. }) | Some(Shape::Rectangle { to, .. }) => {
+                    changed = *to != pos;
+                    *to = pos;
+                }
+                _ => {}
+            }
+        }
+        if released {
+            if let Some(shape) = self.drawing.take() {
+                self.shapes.push(shape);
+                changed = true;
+            }
+        }
+        if changed {
+            self.last_change = Instant::now();
+        }
+    }
+
+    pub fn paint(&self, painter: &Painter, rect: Rect) {
+        for shape in self.shapes.iter().chain(self.drawing.iter()) {
+            shape.paint(painter, rect);
+        }
+    }
+
+    /// Completed and in-progress shapes, for the capture thread to draw into the outgoing frames.
+    pub fn shapes(&self) -> Vec<Shape> {
+        self.shapes.iter().chain(self.drawing.iter()).cloned().collect()
+    }
+}
+
+// Segni dei viewer: ping e tratti brevi inviati sul canale di controllo, mostrati solo
+// sull'anteprima del caster e poi lasciati svanire
+const MARK_LIFETIME: Duration = Duration::from_secs(3);
+const MARK_WIDTH: f32 = 3.0;
+const PING_RADIUS: f32 = 24.0;
+// un tratto più corto di così è un click, quindi un ping
+const PING_MAX_EXTENT: f32 = 0.01;
+// punti più vicini di così non si inviano, e un tratto ha al massimo MAX_MARK_POINTS punti
+const MARK_MIN_STEP: f32 = 0.003;
+const MAX_MARK_POINTS: usize = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarkShape {
+    Ping(Pos2),
+    Stroke(Vec<Pos2>),
+}
+
+/// A viewer's ping or stroke, in coordinates normalised on the stream frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ViewerMark {
+    pub color: Color32,
+    pub shape: MarkShape,
+}
+
+impl ViewerMark {
+    pub fn to_line(&self) -> String {
+        let color = format!("{:02x}{:02x}{:02x}", self.color.r(), self.color.g(), self.color.b());
+        match &self.shape {
+            MarkShape::Ping(pos) => format!("PING {} {} {}", color, pos.x, pos.y),
+            MarkShape::Stroke(points) => {
+                let points: Vec<String> = points.iter().map(|p| format!("{} {}", p.x, p.y)).collect();
+                format!("STROKE {} {}", color, points.join(" "))
+            }
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<ViewerMark> {
+        let mut parts = line.split_whitespace();
+        let kind = parts.next()?;
+        let color = Color32::from_hex(&format!("#{}", parts.next()?)).ok()?;
+        let values: Vec<f32> = parts.map(|v| v.parse::<f32>().ok()).collect::<Option<_>>()?;
+        let points: Vec<Pos2> = values
+            .chunks_exact(2)
+            .take(MAX_MARK_POINTS)
+            .map(|p| Pos2::new(p[0], p[1]).clamp(Pos2::ZERO, Pos2::new(1.0, 1.0)))
+            .collect();
+        let shape = match kind {
+            "PING" => MarkShape::Ping(*points.first()?),
+            "STROKE" if points.len() >= 2 => MarkShape::Stroke(points),
+            _ => return None,
+        };
+        Some(ViewerMark { color, shape })
+    }
+}
+
+/// Viewer marks on screen, each one fading out after a few seconds.
+#[derive(Default)]
+pub struct MarkBoard {
+    marks: Vec<(String, ViewerMark, Instant)>,
+}
+
+impl MarkBoard {
+    pub fn push(&mut self, name: String, mark: ViewerMark) {
+        self.marks.push((name, mark, Instant::now()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.marks.is_empty()
+    }
+
+    pub fn expire(&mut self) {
+        self.marks.retain(|(_, _, time)| time.elapsed() < MARK_LIFETIME);
+    }
+
+    /// Draws the marks over `rect`, with the viewer's name next to each one when it is not empty.
+    pub fn paint(&self, painter: &Painter, rect: Rect) {
+        let to_screen = |p: Pos2| rect.min + p.to_vec2() * rect.size();
+        for (name, mark, time) in &self.marks {
+            let age = time.elapsed().as_secs_f32() / MARK_LIFETIME.as_secs_f32();
+            let color = mark.color.gamma_multiply((1.0 - age).clamp(0.0, 1.0));
+            let label_pos = match &mark.shape {
+                MarkShape::Ping(pos) => {
+                    let center = to_screen(*pos);
+                    // l'anello si allarga mentre svanisce
+                    painter.circle_filled(center, MARK_WIDTH * 1.5, color);
+                    painter.circle_stroke(center, PING_RADIUS * (0.3 + age), Stroke::new(MARK_WIDTH, color));
+                    center + Vec2::splat(PING_RADIUS * 0.5)
+                }
+                MarkShape::Stroke(points) => {
+                    let points: Vec<Pos2> = points.iter().map(|p| to_screen(*p)).collect();
+                    let end = *points.last().unwrap_or(&rect.center());
+                    painter.add(egui::Shape::line(points, Stroke::new(MARK_WIDTH, color)));
+                    end + Vec2::splat(MARK_WIDTH * 2.0)
+                }
+            };
+            if !name.is_empty() {
+                let galley = painter.layout_no_wrap(name.clone(), FontId::proportional(13.0), Color32::WHITE);
+                let label = Rect::from_min_size(label_pos, galley.size()).expand(3.0);
+                painter.rect_filled(label, 3.0, color);
+                painter.galley(label_pos, galley, Color32::WHITE);
+            }
+        }
+    }
+}
+
+/// Receiver side: turns clicks into pings and drags into strokes.
+pub struct MarkPad {
+    pub enabled: bool,
+    pub color: Color32,
+    drawing: Vec<Pos2>,
+}
+
+impl Default for MarkPad {
+    fn default() -> Self {
+        MarkPad { enabled: false, color: Color32::from_rgb(0, 200, 255), drawing: Vec::new() }
+    }
+}
+
+impl MarkPad {
+    /// Pointer handling over the video, `pos` normalised on the frame. Returns the mark to send once the button is released.
+    pub fn pointer(&mut self, pos: Pos2, pressed: bool, down: bool, released: bool) -> Option<ViewerMark> {
+        let pos = pos.clamp(Pos2::ZERO, Pos2::new(1.0, 1.0));
+        if pressed {
+            self.drawing = vec![pos];
+        } else if down && !self.drawing.is_empty() && self.drawing.len() < MAX_MARK_POINTS {
+            if self.drawing.last().is_some_and(|last| last.distance(pos) >= MARK_MIN_STEP) {
+                self.drawing.push(pos);
+            }
+        }
+        if !released || self.drawing.is_empty() {
+            return None;
+        }
+        let points = std::mem::take(&mut self.drawing);
+        let bounds = Rect::from_points(&points);
+        let shape = if bounds.width().max(bounds.height()) < PING_MAX_EXTENT {
+            MarkShape::Ping(points[0])
+        } else {
+            MarkShape::Stroke(points)
+        };
+        Some(ViewerMark { color: self.color, shape })
+    }
+
+    /// Stroke being drawn, shown locally until it is sent.
+    pub fn paint(&self, painter: &Painter, rect: Rect) {
+        if self.drawing.len() >= 2 {
+            let points = self.drawing.iter().map(|p| rect.min + p.to_vec2() * rect.size()).collect();
+            painter.add(egui::Shape::line(points, Stroke::new(MARK_WIDTH, self.color)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // passare sopra all'anteprima senza disegnare non deve tenere in vita le annotazioni
+    #[test]
+    fn hovering_does_not_delay_expiry() {
+        let ctx = Context::default();
+        let mut layer = AnnotationLayer { timeout_secs: 1, ..AnnotationLayer::default() };
+        layer.pointer(&ctx, Pos2::new(0.1, 0.1), true, true, false);
+        layer.pointer(&ctx, Pos2::new(0.5, 0.5), false, true, false);
+        layer.pointer(&ctx, Pos2::new(0.5, 0.5), false, false, true);
+        assert!(!layer.is_empty());
+
+        layer.last_change = Instant::now() - Duration::from_secs(2);
+        layer.pointer(&ctx, Pos2::new(0.7, 0.7), false, false, false);
+        layer.expire();
+        assert!(layer.is_empty());
+    }
+}
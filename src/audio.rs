@@ -0,0 +1,306 @@
+use gstreamer::prelude::*;
+use gstreamer::ElementFactory;
+use gstreamer_app::{gst, AppSink, AppSinkCallbacks};
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use crate::receiver::RtpSource;
+use crate::streaming::Transport;
+
+// L'audio viaggia come secondo stream RTP Opus accanto al video, nella sessione 1 dello stesso rtpbin:
+// con i sender report RTCP il receiver riporta i due flussi sullo stesso orologio, lip_sync_ms
+// resta solo una correzione fine per i ritardi dei dispositivi
+const AUDIO_PT: u32 = 97;
+const OPUS_BITRATE: u32 = 96_000;
+const SAMPLE_RATE: i32 = 48_000;
+const CHANNELS: u8 = 2;
+// campioni di uscita per callback di SDL, 20 ms
+const SDL_BUFFER_SAMPLES: u16 = 960;
+// l'appsink consegna già all'istante giusto, la coda deve solo coprire una callback di SDL
+// più il pacchetto Opus (20 ms) in arrivo; oltre si scartano i campioni più vecchi
+const MAX_QUEUED_SAMPLES: usize = 2 * SDL_BUFFER_SAMPLES as usize * CHANNELS as usize;
+const LEVEL_INTERVAL_NS: u64 = 50_000_000;
+// il meter parte da -60 dB
+pub const METER_FLOOR_DB: f64 = -60.0;
+pub const MAX_VOLUME_PERCENT: u32 = 150;
+// correzione fine per i ritardi dei dispositivi, l'allineamento lo fa rtpbin
+pub const MAX_LIP_SYNC_MS: i32 = 250;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct AudioSettings {
+    // quello che esce dalle casse del caster (monitor di PulseAudio/PipeWire, loopback WASAPI)
+    pub system: bool,
+    pub microphone: bool,
+}
+
+impl AudioSettings {
+    pub fn enabled(&self) -> bool {
+        self.system || self.microphone
+    }
+}
+
+// sorgente di sistema per piattaforma: il monitor del dispositivo di uscita predefinito
+fn system_source() -> Option<&'static str> {
+    let (factory, description) = if cfg!(target_os = "windows") {
+        ("wasapisrc", "wasapisrc loopback=true low-latency=true")
+    } else {
+        ("pulsesrc", "pulsesrc device=@DEFAULT_MONITOR@")
+    };
+    ElementFactory::find(factory).map(|_| description)
+}
+
+fn microphone_source() -> Option<&'static str> {
+    ElementFactory::find("autoaudiosrc").map(|_| "autoaudiosrc")
+}
+
+fn opus_available() -> bool {
+    ElementFactory::find("opusenc").is_some() && ElementFactory::find("rtpopuspay").is_some()
+}
+
+/// Whether system audio can be captured on this machine.
+pub fn system_audio_available() -> bool {
+    gst::init().is_ok() && opus_available() && system_source().is_some()
+}
+
+/// Whether a microphone can be captured on this machine.
+pub fn microphone_available() -> bool {
+    gst::init().is_ok() && opus_available() && microphone_source().is_some()
+}
+
+/// Sender branch: sources mixed, encoded with Opus and sent on UDP and TCP like the video,
+/// through session 1 of the video's rtpbin so both streams get sender reports.
+/// The "audio" tee also feeds the TCP viewers' sinks, which are added per viewer.
+/// Each source has its own volume element so the mute hotkeys work on the running pipeline.
+pub fn sender_description(settings: &AudioSettings) -> String {
+    let sources: Vec<(&str, &str)> = [
+        (settings.system, system_source(), "system_volume"),
+        (settings.microphone, microphone_source(), "mic_volume"),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, source, volume)| if enabled { source.map(|s| (s, volume)) } else { None })
+    .collect();
+    if sources.is_empty() || !opus_available() {
+        return String::new();
+    }
+
+    let mut description = format!(
+        " audiomixer name=mix ! audioconvert ! audioresample ! opusenc bitrate={} frame-size=20 \
+         ! rtpopuspay pt={} ! rtpbin.send_rtp_sink_1 rtpbin.send_rtp_src_1 ! tee name=audio \
+         rtpbin.send_rtcp_src_1 ! multiudpsink name=audio_rtcpsink sync=false async=false \
+         audio. ! queue ! multiudpsink name=audiosink",
+        OPUS_BITRATE, AUDIO_PT
+    );
+    for (source, volume) in sources {
+        description.push_str(&format!(
+            " {} ! audioconvert ! audioresample ! volume name={} ! queue ! mix.",
+            source, volume
+        ));
+    }
+    description
+}
+
+/// Mutes the sources of a running sender pipeline.
+pub fn set_muted(pipeline: &gst::Pipeline, system: bool, microphone: bool) {
+    for (name, muted) in [("system_volume", system), ("mic_volume", microphone)] {
+        if let Some(volume) = pipeline.by_name(name) {
+            if volume.property::<bool>("mute") != muted {
+                volume.set_property("mute", muted);
+            }
+        }
+    }
+}
+
+/// Receiver branch on session 1 of the video's rtpbin, which syncs it to the video with the sender reports.
+/// rtpbin's audio pad is linked to "audio_rtp" when it appears. The appsink keeps the timing
+/// (and the lip-sync offset), SDL only plays what it receives. SRT carries only the video stream.
+pub fn receiver_description(source: &RtpSource) -> String {
+    let rtp_caps = format!("media=audio,clock-rate=48000,encoding-name=OPUS,payload={}", AUDIO_PT);
+    let source = match (source.transport, source.tcp) {
+        (Transport::Udp, _) => format!("udpsrc name=audio_udpsrc port=0 reuse=false caps=\"application/x-rtp,{}\"", rtp_caps),
+        // il caster ha aperto la porta solo per questa sessione, 0 se non ha l'audio
+        (Transport::Tcp, Some(tcp)) if tcp.audio > 0 => format!(
+            "tcpclientsrc host={} port={} ! application/x-rtp-stream,{} ! rtpstreamdepay",
+            source.address, tcp.audio, rtp_caps
+        ),
+        _ => return String::new(),
+    };
+    if ElementFactory::find("opusdec").is_none() {
+        return String::new();
+    }
+    format!(
+        " {} ! rtpbin.recv_rtp_sink_1 \
+         udpsrc name=audio_rtcp_udpsrc port=0 reuse=false caps=\"application/x-rtcp\" ! rtpbin.recv_rtcp_sink_1 \
+         identity name=audio_rtp ! rtpopusdepay ! opusdec \
+         ! audioconvert ! audioresample ! audio/x-raw,format=F32LE,rate={},channels={},layout=interleaved \
+         ! volume name=audio_volume ! level name=audio_level interval={} post-messages=true \
+         ! appsink name=audiosink sync=true",
+        source, SAMPLE_RATE, CHANNELS, LEVEL_INTERVAL_NS
+    )
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PlaybackSettings {
+    // None = dispositivo predefinito di SDL
+    pub device: Option<String>,
+    pub volume_percent: u32,
+    pub muted: bool,
+    // positivo ritarda l'audio rispetto al video, negativo lo anticipa
+    pub lip_sync_ms: i32,
+}
+
+impl Default for PlaybackSettings {
+    fn default() -> Self {
+        PlaybackSettings { device: None, volume_percent: 100, muted: false, lip_sync_ms: 0 }
+    }
+}
+
+thread_local! {
+    // SDL si inizializza una volta sola, il sottosistema audio tiene vivo il contesto
+    static SDL_AUDIO: RefCell<Option<AudioSubsystem>> = const { RefCell::new(None) };
+}
+
+fn audio_subsystem() -> Result<AudioSubsystem, String> {
+    SDL_AUDIO.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        if let Some(audio) = cell.as_ref() {
+            return Ok(audio.clone());
+        }
+        let audio = sdl2::init()?.audio()?;
+        *cell = Some(audio.clone());
+        Ok(audio)
+    })
+}
+
+/// Names of the playback devices SDL can open.
+pub fn output_devices() -> Vec<String> {
+    let Ok(audio) = audio_subsystem() else {
+        return Vec::new();
+    };
+    (0..audio.num_audio_playback_devices().unwrap_or(0))
+        .filter_map(|i| audio.audio_playback_device_name(i).ok())
+        .collect()
+}
+
+// campioni decodificati, scritti dal thread di GStreamer e letti dalla callback di SDL
+#[derive(Default)]
+struct SampleQueue {
+    samples: Mutex<VecDeque<f32>>,
+}
+
+impl SampleQueue {
+    fn push(&self, data: &[u8]) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.extend(data.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])));
+        let excess = samples.len().saturating_sub(MAX_QUEUED_SAMPLES);
+        samples.drain(..excess);
+    }
+
+    fn clear(&self) {
+        self.samples.lock().unwrap().clear();
+    }
+}
+
+struct SampleFeeder {
+    queue: Arc<SampleQueue>,
+}
+
+impl AudioCallback for SampleFeeder {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let mut samples = self.queue.samples.lock().unwrap();
+        // se mancano campioni si riempie di silenzio
+        for sample in out.iter_mut() {
+            *sample = samples.pop_front().unwrap_or(0.0);
+        }
+    }
+}
+
+/// Plays the audio branch of a receiver pipeline on an SDL output device.
+pub struct AudioPlayer {
+    queue: Arc<SampleQueue>,
+    device: Option<AudioDevice<SampleFeeder>>,
+    device_name: Option<String>,
+    level_db: f64,
+}
+
+impl Default for AudioPlayer {
+    fn default() -> Self {
+        AudioPlayer { queue: Arc::new(SampleQueue::default()), device: None, device_name: None, level_db: METER_FLOOR_DB }
+    }
+}
+
+impl AudioPlayer {
+    /// Routes the samples of a new pipeline to the player; the device opens with the first pipeline that has audio.
+    pub fn attach(&mut self, pipeline: &gst::Pipeline, settings: &PlaybackSettings) {
+        self.queue.clear();
+        let Some(appsink) = pipeline.by_name("audiosink").and_then(|e| e.downcast::<AppSink>().ok()) else {
+            return;
+        };
+        let queue = self.queue.clone();
+        appsink.set_callbacks(
+            AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                    let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                    queue.push(map.as_slice());
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+        if self.device.is_none() {
+            self.open(settings.device.clone());
+        }
+        self.apply(pipeline, settings);
+    }
+
+    fn open(&mut self, name: Option<String>) {
+        self.device = None;
+        self.device_name = name.clone();
+        let desired = AudioSpecDesired { freq: Some(SAMPLE_RATE), channels: Some(CHANNELS), samples: Some(SDL_BUFFER_SAMPLES) };
+        let queue = self.queue.clone();
+        match audio_subsystem().and_then(|audio| audio.open_playback(name.as_deref(), &desired, |_| SampleFeeder { queue })) {
+            Ok(device) => {
+                device.resume();
+                self.device = Some(device);
+            }
+            Err(e) => eprintln!("Impossibile aprire il dispositivo audio: {}", e),
+        }
+    }
+
+    /// Volume, mute and lip-sync are changed on the running pipeline, the device is reopened only if it changed.
+    pub fn apply(&mut self, pipeline: &gst::Pipeline, settings: &PlaybackSettings) {
+        // anche senza dispositivo aperto: quello scelto prima può non essersi aperto, si riprova col nuovo
+        if settings.device != self.device_name && pipeline.by_name("audiosink").is_some() {
+            self.open(settings.device.clone());
+        }
+        if let Some(volume) = pipeline.by_name("audio_volume") {
+            volume.set_property("volume", settings.volume_percent as f64 / 100.0);
+            volume.set_property("mute", settings.muted);
+        }
+        if let Some(sink) = pipeline.by_name("audiosink") {
+            sink.set_property("ts-offset", settings.lip_sync_ms as i64 * 1_000_000);
+        }
+    }
+
+    /// Picks up the level messages of the audio branch, returns false for the other messages.
+    pub fn handle_message(&mut self, msg: &gst::MessageRef) -> bool {
+        let Some(structure) = msg.structure().filter(|s| s.name() == "level") else {
+            return false;
+        };
+        if let Ok(peaks) = structure.get::<gst::glib::ValueArray>("peak") {
+            self.level_db = peaks.iter()
+                .filter_map(|v| v.get::<f64>().ok())
+                .fold(METER_FLOOR_DB, f64::max);
+        }
+        true
+    }
+
+    /// Peak level of the last interval in dB, METER_FLOOR_DB when silent.
+    pub fn level_db(&self) -> f64 {
+        self.level_db
+    }
+}
@@ -1,10 +1,358 @@
 #![allow(dead_code)]
 pub mod capture {
 
-    use egui::{pos2, ColorImage};
+    use egui::{pos2, ColorImage, Rect};
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::VecDeque;
+    use std::hash::Hasher;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
     use xcap::image::{RgbaImage};
     use xcap::{Window, XCapError};
     use xcap::Monitor;
+    use crate::annotation::Shape;
+    use crate::cursor::{CursorSample, CursorSettings, CursorTracker};
+    use crate::remote::{screen_area, window_area};
+    use crate::streaming::FrameInput;
+
+    //polling period used in "on change only" mode, frames are sent only if the screen changed
+    const ON_CHANGE_POLL_FPS: u32 = 30;
+    const FPS_WINDOW: Duration = Duration::from_secs(1);
+    const TILE_SIZE: usize = 64;
+    //an unchanged frame is still sent this often, so new viewers get a picture
+    pub const KEEPALIVE_PERIOD: Duration = Duration::from_secs(1);
+    const FRAME_QUEUE_CAPACITY: usize = 4;
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum CaptureRate {
+        Fps(u32),
+        OnChange,
+    }
+
+    impl CaptureRate {
+        pub const MIN_FPS: u32 = 5;
+        pub const MAX_FPS: u32 = 60;
+
+        pub fn nominal_fps(&self) -> u32 {
+            match self {
+                CaptureRate::Fps(fps) => (*fps).clamp(Self::MIN_FPS, Self::MAX_FPS),
+                CaptureRate::OnChange => ON_CHANGE_POLL_FPS,
+            }
+        }
+
+        pub fn period(&self) -> Duration {
+            Duration::from_micros(1_000_000 / self.nominal_fps() as u64)
+        }
+    }
+
+    //measures the real capture rate over the last second
+    #[derive(Default)]
+    pub struct FpsCounter {
+        frames: VecDeque<Instant>,
+    }
+
+    impl FpsCounter {
+        pub fn tick(&mut self) {
+            let now = Instant::now();
+            self.frames.push_back(now);
+            while let Some(first) = self.frames.front() {
+                if now.duration_since(*first) > FPS_WINDOW {
+                    self.frames.pop_front();
+                } else {
+                    break;
+                }
+            }
+        }
+
+        pub fn fps(&self) -> f32 {
+            let recent = self.frames.iter().filter(|t| t.elapsed() <= FPS_WINDOW).count();
+            recent as f32 / FPS_WINDOW.as_secs_f32()
+        }
+    }
+
+    //detects which parts of the screen changed by hashing fixed size tiles
+    #[derive(Default)]
+    pub struct DamageTracker {
+        size: [usize; 2],
+        tiles: Vec<u64>,
+        dirty_tiles: usize,
+        last_sent: Option<Instant>,
+    }
+
+    impl DamageTracker {
+        //returns true if the frame has to be sent: something changed or the keepalive expired
+        pub fn update(&mut self, img: &ColorImage) -> bool {
+            let tiles = tile_hashes(img);
+            self.dirty_tiles = if img.size != self.size || tiles.len() != self.tiles.len() {
+                tiles.len()
+            } else {
+                tiles.iter().zip(self.tiles.iter()).filter(|(a, b)| a != b).count()
+            };
+            self.size = img.size;
+            self.tiles = tiles;
+
+            let keepalive = self.last_sent.map_or(true, |t| t.elapsed() >= KEEPALIVE_PERIOD);
+            if self.dirty_tiles > 0 || keepalive {
+                self.last_sent = Some(Instant::now());
+                true
+            } else {
+                false
+            }
+        }
+
+        pub fn dirty_tiles(&self) -> usize {
+            self.dirty_tiles
+        }
+
+        pub fn reset(&mut self) {
+            *self = DamageTracker::default();
+        }
+    }
+
+    fn tile_hashes(img: &ColorImage) -> Vec<u64> {
+        let [width, height] = img.size;
+        let raw = img.as_raw();
+        let tiles_x = width.div_ceil(TILE_SIZE);
+        let tiles_y = height.div_ceil(TILE_SIZE);
+        let mut hashes = Vec::with_capacity(tiles_x * tiles_y);
+
+        for ty in 0..tiles_y {
+            for tx in 0..tiles_x {
+                let mut hasher = DefaultHasher::new();
+                let x0 = tx * TILE_SIZE;
+                let x1 = (x0 + TILE_SIZE).min(width);
+                for y in (ty * TILE_SIZE)..((ty + 1) * TILE_SIZE).min(height) {
+                    let start = (y * width + x0) * 4;
+                    let end = (y * width + x1) * 4;
+                    hasher.write(&raw[start..end]);
+                }
+                hashes.push(hasher.finish());
+            }
+        }
+        hashes
+    }
+
+    //bounded ring buffer between the capture thread and the ui, the oldest frame is dropped when full
+    pub struct FrameQueue {
+        frames: Mutex<VecDeque<ColorImage>>,
+        capacity: usize,
+        dropped: AtomicUsize,
+    }
+
+    impl FrameQueue {
+        pub fn new(capacity: usize) -> Self {
+            FrameQueue {
+                frames: Mutex::new(VecDeque::with_capacity(capacity)),
+                capacity,
+                dropped: AtomicUsize::new(0),
+            }
+        }
+
+        pub fn push(&self, frame: ColorImage) {
+            let mut frames = self.frames.lock().unwrap();
+            if frames.len() >= self.capacity {
+                frames.pop_front();
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            frames.push_back(frame);
+        }
+
+        pub fn drain(&self) -> Vec<ColorImage> {
+            self.frames.lock().unwrap().drain(..).collect()
+        }
+
+        pub fn dropped(&self) -> usize {
+            self.dropped.load(Ordering::Relaxed)
+        }
+    }
+
+    //what a stream captures, both kinds are identified by their xcap id
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum CaptureSource {
+        Monitor(u32),
+        Window(u32),
+    }
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub struct CaptureConfig {
+        pub source: CaptureSource,
+        pub crop: Option<Rect>,
+        pub rate: CaptureRate,
+        pub paused: bool,
+        pub cursor: CursorSettings,
+    }
+
+    //captures the screen on its own thread so a slow screenshot does not block the ui,
+    //the same thread feeds the encoder while the ui only gets the frames for the preview
+    pub struct CaptureWorker {
+        running: Arc<AtomicBool>,
+        config: Arc<Mutex<CaptureConfig>>,
+        queue: Arc<FrameQueue>,
+        input: Arc<Mutex<Option<FrameInput>>>,
+        //annotations composited into the outgoing frames only
+        overlay: Arc<Mutex<Vec<Shape>>>,
+        capture_fps: Arc<Mutex<FpsCounter>>,
+        //latest cursor position and the clicks not taken yet, for the metadata mode
+        cursor: Arc<Mutex<CursorSample>>,
+        //desktop area of the latest frame, a window can move while it is captured
+        area: Arc<Mutex<Option<Rect>>>,
+    }
+
+    impl CaptureWorker {
+        //on_frame is called every time a new frame is queued, e.g. to request a repaint
+        pub fn start(config: CaptureConfig, on_frame: impl Fn() + Send + 'static) -> Self {
+            let running = Arc::new(AtomicBool::new(true));
+            let config = Arc::new(Mutex::new(config));
+            let queue = Arc::new(FrameQueue::new(FRAME_QUEUE_CAPACITY));
+            let capture_fps = Arc::new(Mutex::new(FpsCounter::default()));
+            let cursor = Arc::new(Mutex::new(CursorSample::default()));
+            let area = Arc::new(Mutex::new(None));
+            let input: Arc<Mutex<Option<FrameInput>>> = Arc::new(Mutex::new(None));
+            let overlay: Arc<Mutex<Vec<Shape>>> = Arc::new(Mutex::new(Vec::new()));
+
+            {
+                let running = running.clone();
+                let config = config.clone();
+                let queue = queue.clone();
+                let input = input.clone();
+                let overlay = overlay.clone();
+                let capture_fps = capture_fps.clone();
+                let cursor = cursor.clone();
+                let area = area.clone();
+                thread::spawn(move || {
+                    let mut monitor: Option<Monitor> = None;
+                    let mut window: Option<Window> = None;
+                    let mut damage = DamageTracker::default();
+                    let mut tracker = CursorTracker::default();
+
+                    while running.load(Ordering::Relaxed) {
+                        let started = Instant::now();
+                        let config = *config.lock().unwrap();
+
+                        if config.paused {
+                            damage.reset();
+                        } else {
+                            //sources are looked up here because xcap handles can't cross threads
+                            let captured = match config.source {
+                                CaptureSource::Monitor(id) => {
+                                    if monitor.as_ref().map(|m| m.id()) != Some(id) {
+                                        monitor = get_monitors().into_iter().find(|m| m.id() == id);
+                                    }
+                                    monitor.as_ref().and_then(|m| Some((capture(m).ok()?, screen_area(m, config.crop))))
+                                }
+                                CaptureSource::Window(id) => {
+                                    //the position is refreshed every frame, a closed window is looked up again
+                                    if window.as_ref().map(|w| w.id()) != Some(id) || window.as_mut().is_some_and(|w| w.refresh().is_err()) {
+                                        window = get_windows().into_iter().find(|w| w.id() == id);
+                                    }
+                                    window.as_ref()
+                                        .filter(|w| !w.is_minimized())
+                                        .and_then(|w| Some((capture_window(w).ok()?, window_area(w, config.crop))))
+                                }
+                            };
+                            if let Some((img, screen)) = captured {
+                                capture_fps.lock().unwrap().tick();
+                                let mut color_img = ColorImage::from_rgba_unmultiplied([img.width() as usize, img.height() as usize], img.as_raw());
+                                if let Some(rect) = config.crop {
+                                    color_img = crop_color_image(&color_img, rect.min.x as u32, rect.min.y as u32, rect.width() as u32, rect.height() as u32);
+                                }
+                                //the cursor is drawn before the damage check, so moving it counts as a change
+                                let sample = tracker.sample(screen);
+                                tracker.composite(&mut color_img, &sample, &config.cursor);
+                                {
+                                    let mut shared = cursor.lock().unwrap();
+                                    shared.pos = sample.pos;
+                                    shared.clicks.extend(sample.clicks);
+                                }
+                                *area.lock().unwrap() = Some(screen);
+                                //unchanged frames are neither shown nor streamed, apart from the keepalive
+                                if damage.update(&color_img) {
+                                    if let Some(input) = input.lock().unwrap().as_ref() {
+                                        let overlay = overlay.lock().unwrap();
+                                        if overlay.is_empty() {
+                                            input.push(&color_img);
+                                        } else {
+                                            let mut annotated = color_img.clone();
+                                            for shape in overlay.iter() {
+                                                shape.composite(&mut annotated);
+                                            }
+                                            input.push(&annotated);
+                                        }
+                                    }
+                                    queue.push(color_img);
+                                    on_frame();
+                                }
+                            }
+                        }
+
+                        let period = config.rate.period();
+                        let elapsed = started.elapsed();
+                        if elapsed < period {
+                            thread::sleep(period - elapsed);
+                        }
+                    }
+                });
+            }
+
+            CaptureWorker {
+                running,
+                config,
+                queue,
+                input,
+                overlay,
+                capture_fps,
+                cursor,
+                area,
+            }
+        }
+
+        pub fn configure(&self, config: CaptureConfig) {
+            *self.config.lock().unwrap() = config;
+        }
+
+        //None while the stream is not running
+        pub fn set_input(&self, input: Option<FrameInput>) {
+            *self.input.lock().unwrap() = input;
+        }
+
+        pub fn set_overlay(&self, shapes: Vec<Shape>) {
+            *self.overlay.lock().unwrap() = shapes;
+        }
+
+        pub fn frames(&self) -> Vec<ColorImage> {
+            self.queue.drain()
+        }
+
+        //clicks are returned only once
+        pub fn take_cursor(&self) -> CursorSample {
+            let mut cursor = self.cursor.lock().unwrap();
+            CursorSample { pos: cursor.pos, clicks: std::mem::take(&mut cursor.clicks) }
+        }
+
+        //None until the first frame is captured
+        pub fn area(&self) -> Option<Rect> {
+            *self.area.lock().unwrap()
+        }
+
+        pub fn capture_fps(&self) -> f32 {
+            self.capture_fps.lock().unwrap().fps()
+        }
+
+        pub fn dropped_frames(&self) -> usize {
+            self.queue.dropped()
+        }
+    }
+
+    //the thread is only told to stop, a screenshot in progress must not block the ui:
+    //it ends on its own after the current frame, which no longer reaches the encoder
+    impl Drop for CaptureWorker {
+        fn drop(&mut self) {
+            self.running.store(false, Ordering::Relaxed);
+            self.input.lock().unwrap().take();
+        }
+    }
 
     pub fn primary_monitor() -> Result<Monitor, XCapError> {
         match Monitor::all() {
@@ -31,6 +379,14 @@ pub mod capture {
         }
     }
 
+    //minimized windows and the ones without a title can't be picked as a source
+    pub fn get_windows() -> Vec<Window> {
+        match Window::all() {
+            Ok(w) => w.into_iter().filter(|w| !w.is_minimized() && !w.title().is_empty()).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
     pub fn capture(monitor: &Monitor) -> Result<RgbaImage, XCapError> {
         match monitor.capture_image() {
             Ok(img) => {
@@ -125,4 +481,40 @@ pub mod capture {
         None
     }
 
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use egui::Color32;
+
+        //frame filled with its sequence number, so the survivors can be told apart
+        fn numbered_frame(n: u8) -> ColorImage {
+            ColorImage::new([4, 4], Color32::from_gray(n))
+        }
+
+        //slow consumer: the producer keeps pushing while nobody drains the queue
+        #[test]
+        fn slow_consumer_keeps_only_the_newest_frame() {
+            const FRAMES: u8 = 10;
+            let queue = FrameQueue::new(1);
+            for n in 0..FRAMES {
+                queue.push(numbered_frame(n));
+            }
+            let frames = queue.drain();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].pixels[0], Color32::from_gray(FRAMES - 1));
+            assert_eq!(queue.dropped(), FRAMES as usize - 1);
+            assert!(queue.drain().is_empty());
+        }
+
+        #[test]
+        fn full_queue_drops_the_oldest_frames_in_order() {
+            let queue = FrameQueue::new(3);
+            for n in 0..5 {
+                queue.push(numbered_frame(n));
+            }
+            let survivors: Vec<Color32> = queue.drain().iter().map(|f| f.pixels[0]).collect();
+            assert_eq!(survivors, [2, 3, 4].map(Color32::from_gray));
+            assert_eq!(queue.dropped(), 2);
+        }
+    }
 }
@@ -0,0 +1,265 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use egui::Pos2;
+use crate::annotation::ViewerMark;
+use crate::remote::InputEvent;
+use crate::streaming::{Codec, TcpPorts, Transport, UdpPorts};
+
+// Canale di controllo TCP tra caster e receiver: un messaggio testuale per riga
+pub const CONTROL_PORT: u16 = 5001;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlMessage {
+    /// receiver -> caster, first message after connecting
+    Hello(String),
+    /// caster -> receiver, codec currently used by the stream
+    Codec(Codec),
+    /// caster -> receiver, whether an Opus stream is sent next to the video
+    Audio(bool),
+    /// receiver -> caster, the picture is corrupted and needs a new keyframe
+    KeyframeRequest,
+    /// receiver -> caster, how the receiver is getting the RTP packets
+    Transport(Transport),
+    /// receiver -> caster, local ports the UDP packets have to be sent to
+    UdpPorts(UdpPorts),
+    /// caster -> receiver, one-time ports opened for this receiver after it asked for TCP
+    TcpPorts(TcpPorts),
+    /// receiver -> caster, asks to control the caster's mouse and keyboard
+    ControlRequest,
+    /// receiver -> caster, gives the control back
+    ControlRelease,
+    /// caster -> receiver, control granted or revoked/denied
+    ControlGrant(bool),
+    /// receiver -> caster, pointer or key event, injected only while control is granted
+    Input(InputEvent),
+    /// receiver -> caster, ping or stroke drawn by the viewer over the video
+    Mark(ViewerMark),
+    /// caster -> receiver, cursor position when it is sent as metadata, None when hidden or off the stream
+    Cursor(Option<Pos2>),
+    /// caster -> receiver, click of the caster's cursor, drawn as a ripple
+    Click(Pos2),
+}
+
+impl ControlMessage {
+    pub fn to_line(&self) -> String {
+        match self {
+            ControlMessage::Hello(name) => format!("HELLO {}", name),
+            ControlMessage::Codec(codec) => format!("CODEC {}", codec.name()),
+            ControlMessage::Audio(enabled) => format!("AUDIO {}", if *enabled { "ON" } else { "OFF" }),
+            ControlMessage::KeyframeRequest => "KEYFRAME".to_string(),
+            ControlMessage::Transport(transport) => format!("TRANSPORT {}", transport.name()),
+            ControlMessage::UdpPorts(udp) => format!("PORTS {} {} {} {}", udp.video, udp.audio, udp.rtcp, udp.audio_rtcp),
+            ControlMessage::TcpPorts(tcp) => format!("TCPPORTS {} {}", tcp.video, tcp.audio),
+            ControlMessage::ControlRequest => "CONTROL REQUEST".to_string(),
+            ControlMessage::ControlRelease => "CONTROL RELEASE".to_string(),
+            ControlMessage::ControlGrant(granted) => format!("CONTROL {}", if *granted { "GRANTED" } else { "REVOKED" }),
+            ControlMessage::Input(event) => format!("INPUT {}", event.to_line()),
+            ControlMessage::Mark(mark) => format!("MARK {}", mark.to_line()),
+            ControlMessage::Cursor(Some(pos)) => format!("CURSOR {} {}", pos.x, pos.y),
+            ControlMessage::Cursor(None) => "CURSOR NONE".to_string(),
+            ControlMessage::Click(pos) => format!("CLICK {} {}", pos.x, pos.y),
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<ControlMessage> {
+        // niente trim sul resto della riga: il testo digitato può finire con uno spazio
+        let line = line.trim_start().trim_end_matches(['\r', '\n']);
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "HELLO" => Some(ControlMessage::Hello(args.to_string())),
+            "CODEC" => Codec::from_name(args).map(ControlMessage::Codec),
+            "AUDIO" => match args {
+                "ON" => Some(ControlMessage::Audio(true)),
+                "OFF" => Some(ControlMessage::Audio(false)),
+                _ => None,
+            },
+            "KEYFRAME" => Some(ControlMessage::KeyframeRequest),
+            "TRANSPORT" => Transport::from_name(args).map(ControlMessage::Transport),
+            "PORTS" => parse_udp_ports(args).map(ControlMessage::UdpPorts),
+            "TCPPORTS" => parse_tcp_ports(args).map(ControlMessage::TcpPorts),
+            "CONTROL" => match args {
+                "REQUEST" => Some(ControlMessage::ControlRequest),
+                "RELEASE" => Some(ControlMessage::ControlRelease),
+                "GRANTED" => Some(ControlMessage::ControlGrant(true)),
+                "REVOKED" => Some(ControlMessage::ControlGrant(false)),
+                _ => None,
+            },
+            "INPUT" => InputEvent::parse(args).map(ControlMessage::Input),
+            "MARK" => ViewerMark::parse(args).map(ControlMessage::Mark),
+            "CURSOR" if args == "NONE" => Some(ControlMessage::Cursor(None)),
+            "CURSOR" => parse_pos(args).map(|pos| ControlMessage::Cursor(Some(pos))),
+            "CLICK" => parse_pos(args).map(ControlMessage::Click),
+            _ => None,
+        }
+    }
+}
+
+fn parse_pos(args: &str) -> Option<Pos2> {
+    let mut parts = args.split_whitespace();
+    Some(Pos2::new(parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+fn parse_udp_ports(args: &str) -> Option<UdpPorts> {
+    let mut parts = args.split_whitespace();
+    let mut port = || parts.next()?.parse().ok();
+    Some(UdpPorts { video: port()?, audio: port()?, rtcp: port()?, audio_rtcp: port()? })
+}
+
+fn parse_tcp_ports(args: &str) -> Option<TcpPorts> {
+    let mut parts = args.split_whitespace();
+    let mut port = || parts.next()?.parse().ok();
+    Some(TcpPorts { video: port()?, audio: port()? })
+}
+
+fn write_message(mut stream: &TcpStream, msg: &ControlMessage) -> io::Result<()> {
+    stream.write_all(format!("{}\n", msg.to_line()).as_bytes())
+}
+
+// Legge le righe dallo stream finché la connessione resta aperta
+fn read_messages(stream: TcpStream, mut on_message: impl FnMut(ControlMessage) -> bool) {
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let Ok(line) = line else { break };
+        match ControlMessage::parse(&line) {
+            Some(msg) => {
+                if !on_message(msg) {
+                    break;
+                }
+            }
+            None => eprintln!("Messaggio di controllo sconosciuto: {}", line),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ControlEvent {
+    Connected(usize, IpAddr),
+    Message(usize, ControlMessage),
+    Disconnected(usize),
+}
+
+/// Caster side of the control channel: accepts receivers and forwards their messages.
+pub struct ControlServer {
+    clients: Arc<Mutex<Vec<(usize, TcpStream)>>>,
+    events: mpsc::Receiver<ControlEvent>,
+    running: Arc<AtomicBool>,
+}
+
+impl ControlServer {
+    pub fn start(port: u16) -> io::Result<ControlServer> {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))?;
+        listener.set_nonblocking(true)?;
+
+        let clients: Arc<Mutex<Vec<(usize, TcpStream)>>> = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let (tx, rx) = mpsc::channel();
+
+        let clients_clone = clients.clone();
+        let running_clone = running.clone();
+        thread::spawn(move || {
+            let mut next_id = 0;
+            while running_clone.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, addr)) => {
+                        let _ = stream.set_nonblocking(false);
+                        let _ = stream.set_nodelay(true);
+                        let Ok(reader) = stream.try_clone() else { continue };
+                        let id = next_id;
+                        next_id += 1;
+                        clients_clone.lock().unwrap().push((id, stream));
+                        let _ = tx.send(ControlEvent::Connected(id, addr.ip()));
+
+                        let tx = tx.clone();
+                        let clients = clients_clone.clone();
+                        thread::spawn(move || {
+                            read_messages(reader, |msg| tx.send(ControlEvent::Message(id, msg)).is_ok());
+                            clients.lock().unwrap().retain(|(i, _)| *i != id);
+                            let _ = tx.send(ControlEvent::Disconnected(id));
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+                    Err(e) => {
+                        eprintln!("Errore del canale di controllo: {}", e);
+                        thread::sleep(ACCEPT_POLL);
+                    }
+                }
+            }
+        });
+
+        Ok(ControlServer { clients, events: rx, running })
+    }
+
+    pub fn send(&self, id: usize, msg: &ControlMessage) {
+        if let Some((_, stream)) = self.clients.lock().unwrap().iter().find(|(i, _)| *i == id) {
+            if let Err(e) = write_message(stream, msg) {
+                eprintln!("Invio al receiver {} fallito: {}", id, e);
+            }
+        }
+    }
+
+    pub fn broadcast(&self, msg: &ControlMessage) {
+        for (_, stream) in self.clients.lock().unwrap().iter() {
+            let _ = write_message(stream, msg);
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<ControlEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        for (_, stream) in self.clients.lock().unwrap().iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Receiver side of the control channel.
+pub struct ControlClient {
+    stream: TcpStream,
+    incoming: mpsc::Receiver<ControlMessage>,
+}
+
+impl ControlClient {
+    pub fn connect(address: IpAddr, port: u16) -> io::Result<ControlClient> {
+        let stream = TcpStream::connect_timeout(&SocketAddr::new(address, port), CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            read_messages(reader, |msg| tx.send(msg).is_ok());
+        });
+        Ok(ControlClient { stream, incoming: rx })
+    }
+
+    pub fn send(&self, msg: &ControlMessage) -> io::Result<()> {
+        write_message(&self.stream, msg)
+    }
+
+    pub fn try_recv(&self) -> Option<ControlMessage> {
+        self.incoming.try_recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<ControlMessage> {
+        self.incoming.recv_timeout(timeout).ok()
+    }
+
+    pub fn peer_address(&self) -> Option<IpAddr> {
+        self.stream.peer_addr().ok().map(|a| a.ip())
+    }
+}
+
+impl Drop for ControlClient {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
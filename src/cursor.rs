@@ -0,0 +1,322 @@
+use device_query::{DeviceQuery, DeviceState};
+use egui::{Color32, ColorImage, Painter, Pos2, Rect, Stroke, Vec2};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// Il cursore non è nei frame di xcap: la posizione si legge dal sistema e l'immagine dal tema
+// dei cursori, poi si compone nel frame oppure si invia ai receiver come metadato
+const ENLARGED_SCALE: usize = 2;
+const RIPPLE_DURATION: Duration = Duration::from_millis(500);
+// raggio massimo e spessore dell'anello del click, in pixel del frame
+const RIPPLE_RADIUS: f32 = 28.0;
+const RIPPLE_WIDTH: f32 = 3.0;
+const RIPPLE_COLOR: Color32 = Color32::from_rgb(255, 200, 0);
+const DEFAULT_CURSOR_SIZE: u32 = 24;
+const MAX_THEME_DEPTH: usize = 4;
+// dimensione della freccia disegnata dal receiver in modalità metadati, in punti
+const METADATA_CURSOR_SIZE: f32 = 18.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CursorMode {
+    Hidden,
+    Shown,
+    Enlarged,
+    // posizione e click sul canale di controllo, il receiver disegna il cursore sopra al video
+    Metadata,
+}
+
+impl CursorMode {
+    pub const ALL: [CursorMode; 4] = [CursorMode::Hidden, CursorMode::Shown, CursorMode::Enlarged, CursorMode::Metadata];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            CursorMode::Hidden => "Hidden",
+            CursorMode::Shown => "Shown",
+            CursorMode::Enlarged => "Enlarged",
+            CursorMode::Metadata => "Metadata",
+        }
+    }
+
+    fn composited(&self) -> bool {
+        matches!(self, CursorMode::Shown | CursorMode::Enlarged)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CursorSettings {
+    pub mode: CursorMode,
+    // anello che si allarga a ogni click
+    pub ripple: bool,
+}
+
+impl Default for CursorSettings {
+    fn default() -> Self {
+        CursorSettings { mode: CursorMode::Shown, ripple: false }
+    }
+}
+
+/// Cursor position and new clicks, normalised on the captured area.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CursorSample {
+    // None quando il cursore è fuori dall'area catturata
+    pub pos: Option<Pos2>,
+    pub clicks: Vec<Pos2>,
+}
+
+/// Cursor bitmap with premultiplied pixels and the hotspot in pixels.
+struct CursorImage {
+    size: [usize; 2],
+    hotspot: [usize; 2],
+    pixels: Vec<Color32>,
+}
+
+// freccia usata quando il tema non si trova (Windows o tema senza file Xcursor)
+const ARROW: [&str; 19] = [
+    "X",
+    "XX",
+    "X.X",
+    "X..X",
+    "X...X",
+    "X....X",
+    "X.....X",
+    "X......X",
+    "X.......X",
+    "X........X",
+    "X.........X",
+    "X..........X",
+    "X......XXXXX",
+    "X...X..X",
+    "X..XX..X",
+    "X.X  X..X",
+    "XX   X..X",
+    "      X..X",
+    "      XXX",
+];
+
+impl CursorImage {
+    fn arrow() -> CursorImage {
+        let width = ARROW.iter().map(|row| row.len()).max().unwrap_or(0);
+        let mut pixels = vec![Color32::TRANSPARENT; width * ARROW.len()];
+        for (y, row) in ARROW.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                pixels[y * width + x] = match c {
+                    'X' => Color32::BLACK,
+                    '.' => Color32::WHITE,
+                    _ => Color32::TRANSPARENT,
+                };
+            }
+        }
+        CursorImage { size: [width, ARROW.len()], hotspot: [0, 0], pixels }
+    }
+
+    /// Pointer of the current Xcursor theme, the built-in arrow when it can't be found.
+    fn load() -> CursorImage {
+        let theme = std::env::var("XCURSOR_THEME").unwrap_or_else(|_| "default".to_string());
+        let size = std::env::var("XCURSOR_SIZE").ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_CURSOR_SIZE);
+        find_theme_cursor(&theme, size, 0).unwrap_or_else(CursorImage::arrow)
+    }
+}
+
+fn icon_dirs() -> Vec<PathBuf> {
+    if let Ok(path) = std::env::var("XCURSOR_PATH") {
+        return path.split(':').map(PathBuf::from).collect();
+    }
+    let mut dirs = Vec::new();
+    if let Ok(home) = std::env::var("HOME") {
+        dirs.push(PathBuf::from(&home).join(".local/share/icons"));
+        dirs.push(PathBuf::from(&home).join(".icons"));
+    }
+    dirs.push(PathBuf::from("/usr/share/icons"));
+    dirs.push(PathBuf::from("/usr/share/pixmaps"));
+    dirs
+}
+
+// cerca il puntatore nel tema e poi nei temi da cui eredita (index.theme, Inherits=)
+fn find_theme_cursor(theme: &str, size: u32, depth: usize) -> Option<CursorImage> {
+    if depth > MAX_THEME_DEPTH {
+        return None;
+    }
+    for dir in icon_dirs() {
+        for name in ["left_ptr", "default", "arrow"] {
+            if let Some(image) = fs::read(dir.join(theme).join("cursors").join(name)).ok().and_then(|data| parse_xcursor(&data, size)) {
+                return Some(image);
+            }
+        }
+    }
+    let inherits = icon_dirs().into_iter().find_map(|dir| {
+        let index = fs::read_to_string(dir.join(theme).join("index.theme")).ok()?;
+        index.lines().find_map(|line| line.trim().strip_prefix("Inherits=").map(str::to_string))
+    })?;
+    inherits.split([',', ';']).map(str::trim).filter(|t| !t.is_empty() && *t != theme)
+        .find_map(|parent| find_theme_cursor(parent, size, depth + 1))
+}
+
+// formato Xcursor: intestazione, indice dei blocchi e immagini ARGB premoltiplicate little-endian
+fn parse_xcursor(data: &[u8], size: u32) -> Option<CursorImage> {
+    const IMAGE_TYPE: u32 = 0xfffd_0002;
+    let u32_at = |offset: usize| -> Option<u32> {
+        data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    if data.get(0..4)? != b"Xcur" {
+        return None;
+    }
+    let header = u32_at(4)? as usize;
+    let entries = u32_at(12)? as usize;
+    // la dimensione nominale più vicina a quella richiesta
+    let position = (0..entries)
+        .filter_map(|i| {
+            let entry = header + i * 12;
+            (u32_at(entry)? == IMAGE_TYPE).then_some((u32_at(entry + 4)?, u32_at(entry + 8)? as usize))
+        })
+        .min_by_key(|(nominal, _)| nominal.abs_diff(size))?
+        .1;
+    let width = u32_at(position + 16)? as usize;
+    let height = u32_at(position + 20)? as usize;
+    let hotspot = [u32_at(position + 24)? as usize, u32_at(position + 28)? as usize];
+    let pixels_start = position + 36;
+    let pixels = (0..width * height)
+        .map(|i| {
+            let argb = u32_at(pixels_start + i * 4)?;
+            let [b, g, r, a] = argb.to_le_bytes();
+            Some(Color32::from_rgba_premultiplied(r, g, b, a))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(CursorImage { size: [width, height], hotspot, pixels })
+}
+
+fn blend(image: &mut ColorImage, x: usize, y: usize, src: Color32) {
+    let dst = &mut image.pixels[y * image.size[0] + x];
+    let keep = 255 - src.a() as u32;
+    let mix = |s: u8, d: u8| (s as u32 + d as u32 * keep / 255).min(255) as u8;
+    *dst = Color32::from_rgba_premultiplied(mix(src.r(), dst.r()), mix(src.g(), dst.g()), mix(src.b(), dst.b()), mix(src.a(), dst.a()));
+}
+
+/// Follows the system cursor from the capture thread and draws it into the frames.
+pub struct CursorTracker {
+    device: Option<DeviceState>,
+    image: CursorImage,
+    pressed: bool,
+    ripples: VecDeque<(Pos2, Instant)>,
+}
+
+impl Default for CursorTracker {
+    fn default() -> Self {
+        CursorTracker {
+            device: DeviceState::checked_new(),
+            image: CursorImage::load(),
+            pressed: false,
+            ripples: VecDeque::new(),
+        }
+    }
+}
+
+impl CursorTracker {
+    /// Reads the cursor; `area` is the captured part of the desktop, in desktop coordinates.
+    pub fn sample(&mut self, area: Rect) -> CursorSample {
+        let Some(device) = &self.device else {
+            return CursorSample::default();
+        };
+        let mouse = device.get_mouse();
+        let pos = Pos2::new(mouse.coords.0 as f32, mouse.coords.1 as f32);
+        let normalized = area.contains(pos).then(|| ((pos - area.min) / area.size()).to_pos2());
+
+        // i pulsanti partono dall'indice 1, qualunque pulsante conta come click
+        let pressed = mouse.button_pressed.iter().skip(1).any(|b| *b);
+        let mut clicks = Vec::new();
+        if pressed && !self.pressed {
+            if let Some(pos) = normalized {
+                clicks.push(pos);
+                self.ripples.push_back((pos, Instant::now()));
+            }
+        }
+        self.pressed = pressed;
+        while self.ripples.front().is_some_and(|(_, time)| time.elapsed() >= RIPPLE_DURATION) {
+            self.ripples.pop_front();
+        }
+        CursorSample { pos: normalized, clicks }
+    }
+
+    /// Draws the cursor and the click ripples of the last sample into a frame.
+    pub fn composite(&self, image: &mut ColorImage, sample: &CursorSample, settings: &CursorSettings) {
+        let size = Vec2::new(image.size[0] as f32, image.size[1] as f32);
+        if settings.ripple && settings.mode != CursorMode::Metadata {
+            for (pos, time) in &self.ripples {
+                let progress = time.elapsed().as_secs_f32() / RIPPLE_DURATION.as_secs_f32();
+                draw_ring(image, pos.to_vec2() * size, RIPPLE_RADIUS * progress.min(1.0), RIPPLE_COLOR.gamma_multiply(1.0 - progress.min(1.0)));
+            }
+        }
+        let Some(pos) = sample.pos.filter(|_| settings.mode.composited()) else {
+            return;
+        };
+        let scale = if settings.mode == CursorMode::Enlarged { ENLARGED_SCALE } else { 1 };
+        let [width, height] = self.image.size;
+        let origin_x = (pos.x * size.x) as i64 - (self.image.hotspot[0] * scale) as i64;
+        let origin_y = (pos.y * size.y) as i64 - (self.image.hotspot[1] * scale) as i64;
+        for y in 0..height * scale {
+            let iy = origin_y + y as i64;
+            if iy < 0 || iy >= image.size[1] as i64 {
+                continue;
+            }
+            for x in 0..width * scale {
+                let ix = origin_x + x as i64;
+                if ix < 0 || ix >= image.size[0] as i64 {
+                    continue;
+                }
+                let src = self.image.pixels[(y / scale) * width + x / scale];
+                if src.a() > 0 {
+                    blend(image, ix as usize, iy as usize, src);
+                }
+            }
+        }
+    }
+}
+
+fn draw_ring(image: &mut ColorImage, center: Vec2, radius: f32, color: Color32) {
+    let outer = radius + RIPPLE_WIDTH;
+    let min_x = (center.x - outer).floor().max(0.0) as usize;
+    let min_y = (center.y - outer).floor().max(0.0) as usize;
+    let max_x = ((center.x + outer).ceil() as usize).min(image.size[0]);
+    let max_y = ((center.y + outer).ceil() as usize).min(image.size[1]);
+    for y in min_y..max_y {
+        for x in min_x..max_x {
+            let distance = (Vec2::new(x as f32 + 0.5, y as f32 + 0.5) - center).length();
+            // copertura con mezzo pixel di antialiasing sui bordi dell'anello
+            let coverage = (RIPPLE_WIDTH / 2.0 + 0.5 - (distance - radius).abs()).clamp(0.0, 1.0);
+            if coverage > 0.0 {
+                blend(image, x, y, color.gamma_multiply(coverage));
+            }
+        }
+    }
+}
+
+/// Receiver side of the metadata mode: the cursor drawn as a vector arrow over the video.
+pub fn paint_remote_cursor(painter: &Painter, rect: Rect, pos: Pos2, clicks: &[(Pos2, Instant)]) {
+    let to_screen = |p: Pos2| rect.min + p.to_vec2() * rect.size();
+    for (click, time) in clicks {
+        let progress = (time.elapsed().as_secs_f32() / RIPPLE_DURATION.as_secs_f32()).min(1.0);
+        painter.circle_stroke(to_screen(*click), RIPPLE_RADIUS * progress, Stroke::new(RIPPLE_WIDTH, RIPPLE_COLOR.gamma_multiply(1.0 - progress)));
+    }
+    let tip = to_screen(pos);
+    let s = METADATA_CURSOR_SIZE;
+    // testa e coda sono due poligoni convessi: prima il bordo nero più largo, poi il riempimento
+    let head = vec![tip, tip + Vec2::new(0.0, s), tip + Vec2::new(s * 0.72, s * 0.72)];
+    let tail = vec![
+        tip + Vec2::new(s * 0.22, s * 0.7),
+        tip + Vec2::new(s * 0.4, s * 0.64),
+        tip + Vec2::new(s * 0.62, s * 1.1),
+        tip + Vec2::new(s * 0.45, s * 1.17),
+    ];
+    for part in [&head, &tail] {
+        painter.add(egui::Shape::closed_line(part.clone(), Stroke::new(3.0, Color32::BLACK)));
+    }
+    for part in [head, tail] {
+        painter.add(egui::Shape::convex_polygon(part, Color32::WHITE, Stroke::NONE));
+    }
+}
+
+/// Clicks older than the ripple animation are dropped.
+pub fn expire_clicks(clicks: &mut Vec<(Pos2, Instant)>) {
+    clicks.retain(|(_, time)| time.elapsed() < RIPPLE_DURATION);
+}
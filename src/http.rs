@@ -0,0 +1,107 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Server HTTP minimo che espone i file di una cartella, usato per i segmenti HLS
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct FileServer {
+    running: Arc<AtomicBool>,
+}
+
+impl FileServer {
+    pub fn start(port: u16, root: PathBuf) -> io::Result<FileServer> {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))?;
+        listener.set_nonblocking(true)?;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = running.clone();
+        thread::spawn(move || {
+            while running_clone.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let root = root.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = serve(stream, &root) {
+                                eprintln!("Errore del server HTTP: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+                    Err(e) => {
+                        eprintln!("Errore del server HTTP: {}", e);
+                        thread::sleep(ACCEPT_POLL);
+                    }
+                }
+            }
+        });
+
+        Ok(FileServer { running })
+    }
+}
+
+impl Drop for FileServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        Some("ts") => "video/mp2t",
+        Some("html") => "text/html; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+// solo nomi di file nella cartella servita, niente sottocartelle né ".."
+fn resolve(root: &Path, target: &str) -> Option<PathBuf> {
+    let name = target.split('?').next()?.trim_start_matches('/');
+    if name.is_empty() || name.contains('/') || name.contains('\\') || name.starts_with('.') {
+        return None;
+    }
+    Some(root.join(name))
+}
+
+fn serve(mut stream: TcpStream, root: &Path) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // il resto dell'intestazione non serve
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let target = parts.next().unwrap_or("/");
+    let file = resolve(root, target).and_then(|path| fs::read(&path).ok().map(|data| (path, data)));
+
+    match (method, file) {
+        ("GET", Some((path, data))) => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                 Cache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+                content_type(&path),
+                data.len()
+            )?;
+            stream.write_all(&data)?;
+        }
+        ("GET", None) => stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?,
+        _ => stream.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?,
+    }
+    stream.shutdown(Shutdown::Both)
+}
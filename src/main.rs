@@ -3,6 +3,14 @@ mod capture;
 mod ui;
 mod receiver;
 mod streaming;
+mod control;
+mod signalling;
+mod http;
+mod remote;
+mod audio;
+mod annotation;
+mod cursor;
+mod zoom;
 
 use crate::ui::ui::*;
 use crate::capture::capture::*;
@@ -11,7 +19,7 @@ use eframe::{App, Frame, HardwareAcceleration};
 use egui::{Key, Rect, ViewportBuilder, Visuals};
 use egui::{TextureOptions, Vec2};
 use std::cmp::PartialEq;
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 use eframe::emath::Pos2;
@@ -22,12 +30,17 @@ use gstreamer::prelude::{ElementExt, GstBinExt};
 use gstreamer_app::{gst, AppSink, AppSinkCallbacks};
 use xcap::Monitor;
 use crate::State::{MainMenu, MonitorSelection, PortionSelection, Receiver, Sending, KeysCustomization};
+use crate::annotation::{AnnotationLayer, MarkBoard, MarkPad};
+use crate::audio::AudioSettings;
+use crate::cursor::CursorSettings;
+use crate::receiver::{PendingSession, ReceiverSession, ReceiverSettings};
+use crate::remote::GlobalKey;
+use crate::zoom::ZoomView;
+use crate::streaming::{available_codecs, hls_available, hls_reencode_available, hls_url, webrtc_available, webrtc_url, Codec, HlsSettings, QualityPreset, SrtSettings, StreamPorts, StreamSettings, StreamingServer, MAX_STREAMS};
 
 
-const FRAMERATE: usize = 60;
-const FRAMEPERIOD: f64 = 1.0 / (FRAMERATE as f64);
-const WAIT_FRAME: Duration = Duration::from_micros((FRAMEPERIOD * 1_000_000.0) as u64);
 const WINDOW_NAME: &str = "Screen Caster";
+const MIN_WINDOW_SIZE: Vec2 = Vec2::new(450.0, 200.0);
 
 #[derive(PartialEq, PartialOrd, Debug)]
 enum State
@@ -57,6 +70,120 @@ impl Default for MouseDragHandler {
     }
 }
 
+//the toolbar of the presentation mode disappears after this long without mouse movement
+const TOOLBAR_TIMEOUT: Duration = Duration::from_secs(2);
+
+//the main window turned into the picture-in-picture viewer, with where it was before
+struct PipWindow {
+    position: Option<Pos2>,
+    size: Option<Vec2>,
+}
+
+struct PresentationMonitor {
+    id: u32,
+    name: String,
+    //top-left corner in points, where the window is moved before going fullscreen
+    position: Pos2,
+}
+
+//fullscreen receiver view without panels
+struct Presentation {
+    active: bool,
+    //None = the monitor the window is already on
+    monitor: Option<u32>,
+    monitors: Vec<PresentationMonitor>,
+    last_motion: Instant,
+    toolbar: Option<Rect>,
+}
+
+impl Default for Presentation {
+    fn default() -> Self {
+        let monitors = get_monitors().iter().map(|m| {
+            let scale = m.scale_factor().max(1.0);
+            PresentationMonitor {
+                id: m.id(),
+                name: m.name().to_string(),
+                position: Pos2::new(m.x() as f32 / scale, m.y() as f32 / scale),
+            }
+        }).collect();
+        Presentation {
+            active: false,
+            monitor: None,
+            monitors,
+            last_motion: Instant::now(),
+            toolbar: None,
+        }
+    }
+}
+
+impl Presentation {
+    fn target_position(&self) -> Option<Pos2> {
+        self.monitors.iter().find(|m| Some(m.id) == self.monitor).map(|m| m.position)
+    }
+
+    fn moved(&mut self) {
+        self.last_motion = Instant::now();
+    }
+
+    fn toolbar_visible(&self) -> bool {
+        self.last_motion.elapsed() < TOOLBAR_TIMEOUT
+    }
+}
+
+//one connected caster: its session, the queue its pipeline fills and the texture it is shown with
+struct ReceiverTile {
+    session: ReceiverSession,
+    frames: Arc<FrameQueue>,
+    texture: Option<TextureHandle>,
+}
+
+impl ReceiverTile {
+    fn new(session: ReceiverSession, frames: Arc<FrameQueue>) -> Self {
+        ReceiverTile { session, frames, texture: None }
+    }
+}
+
+//one of the streams published by the sender, with its own capture area, settings and ports
+struct SenderStream {
+    ports: StreamPorts,
+    source: CaptureSource,
+    //monitor or window title, only for the stream list
+    source_name: String,
+    crop: Option<Rect>,
+    settings: StreamSettings,
+    server: Option<StreamingServer>,
+    worker: Option<CaptureWorker>,
+    //newest frame while another stream is shown, so switching does not wait for the next capture
+    preview: Option<ColorImage>,
+    //looked up once, the network interfaces are not enumerated every frame
+    webrtc_url: Option<String>,
+    hls_url: Option<String>,
+}
+
+impl SenderStream {
+    fn new(ports: StreamPorts, monitor: &Monitor, settings: StreamSettings) -> Self {
+        SenderStream {
+            ports,
+            source: CaptureSource::Monitor(monitor.id()),
+            source_name: monitor.name().to_string(),
+            crop: None,
+            settings,
+            server: None,
+            worker: None,
+            preview: None,
+            webrtc_url: webrtc_url(ports),
+            hls_url: hls_url(ports),
+        }
+    }
+
+    fn stop(&mut self) {
+        self.server = None;
+        //stops the capture thread without waiting for it
+        self.worker = None;
+        self.preview = None;
+    }
+}
+
 fn main() -> Result<(), eframe::Error> {
     //configure the native window options
     let vpb = ViewportBuilder {
@@ -64,7 +191,7 @@ fn main() -> Result<(), eframe::Error> {
         app_id: Some("Window id".to_string()),
         position: None,
         inner_size: None,
-        min_inner_size: Some(Vec2::new(450.0, 200.0)),
+        min_inner_size: Some(MIN_WINDOW_SIZE),
         max_inner_size: None,
         clamp_size_to_monitor_size: Some(true),
         fullscreen: None,
@@ -118,18 +245,37 @@ fn main() -> Result<(), eframe::Error> {
 }
 struct MyApp {
 
-    sender_channel: mpsc::Sender<ColorImage>, // Aggiunto canale per inviare frame
     texture: Option<TextureHandle>, // To store the image texture
-    receiver_channel: Option<mpsc::Receiver<ColorImage>>, // Canale per ricevere immagini
-    timer: Instant,
-    video_receiver_started: bool,
+    receivers: Vec<ReceiverTile>, // Un caster per riquadro, i comandi del pannello valgono per quello selezionato
+    focused: usize,
+    grid: bool,
+    receiver_settings: ReceiverSettings,
+    //handshake still running, the tile is added when the caster answers
+    connecting: Option<PendingSession>,
+    //the side panel edits the selected stream, the hotkeys act on all of them
+    streams: Vec<SenderStream>,
+    selected_stream: usize,
+    srt_draft: SrtSettings,
+    hls_draft: HlsSettings,
+    available_codecs: Vec<Codec>,
+    webrtc_available: bool,
+    hls_available: bool,
+    hls_reencode_available: bool,
+    annotations: AnnotationLayer,
+    mark_pad: MarkPad,
+    sent_marks: MarkBoard,
+    zoom: ZoomView,
+    presentation: Presentation,
+    //compact always-on-top window with only the video
+    pip: Option<PipWindow>,
+    //visible part of the received video, for the double click
+    video_area: Option<Rect>,
     state: State,
-    monitor: Monitor,
     main_menu_img: Option<ColorImage>,
     drag: MouseDragHandler,
     monitor_preview: Option<Vec<ColorImage>>,
-    crop: Option<Rect>,
     keys: Vec<(String, Key, bool)>,
+    revoke_key: GlobalKey,
     changing_keys: Option<(String, Key)>,
     ip_address: String,
 
@@ -142,41 +288,147 @@ impl MyApp {
         keys.push(("PAUSE".to_string(), Key::Space, false));
         keys.push(("HIDE".to_string(), Key::H, false));
         keys.push(("TERMINATE".to_string(), Key::Escape, false));
+        keys.push(("REVOKE CONTROL".to_string(), Key::F10, false));
+        keys.push(("MUTE AUDIO".to_string(), Key::M, false));
+        keys.push(("MUTE MIC".to_string(), Key::N, false));
+        keys.push(("ANNOTATE".to_string(), Key::F9, false));
         let main_menu_img = image_from_path("assets/no_signal.jpg");
-        let (tx, rx) = mpsc::channel(); // Crea il canale di comunicazione
+        let available_codecs = available_codecs();
+        let codec = available_codecs.first().copied().unwrap_or(Codec::H264);
 
         MyApp {
             texture: None,
-            video_receiver_started: false,
-            receiver_channel: Some(rx),
-            sender_channel: tx,
-            timer: Instant::now(),
+            receivers: Vec::new(),
+            focused: 0,
+            grid: true,
+            receiver_settings: ReceiverSettings::default(),
+            connecting: None,
+            streams: vec![SenderStream::new(StreamPorts::default(), &monitor, StreamSettings {
+                codec,
+                preset: QualityPreset::Video,
+                framerate: CaptureRate::Fps(QualityPreset::Video.framerate()),
+                fec_percentage: 0,
+                srt: None,
+                webrtc: false,
+                hls: None,
+                audio: AudioSettings::default(),
+                cursor: CursorSettings::default(),
+            })],
+            selected_stream: 0,
+            srt_draft: SrtSettings::default(),
+            hls_draft: HlsSettings::default(),
+            annotations: AnnotationLayer::default(),
+            mark_pad: MarkPad::default(),
+            sent_marks: MarkBoard::default(),
+            zoom: ZoomView::default(),
+            presentation: Presentation::default(),
+            pip: None,
+            video_area: None,
+            available_codecs,
+            webrtc_available: webrtc_available(),
+            hls_available: hls_available(),
+            hls_reencode_available: hls_reencode_available(),
             state: MainMenu,
-            monitor: monitor,
             main_menu_img,
             drag: MouseDragHandler::default(),
             monitor_preview: None,
-            crop: None,
             keys,
+            revoke_key: GlobalKey::default(),
             changing_keys: None,
             ip_address: String::new()
         }
     }
+}
+impl MyApp {
+    //the side panel edits the settings of the focused caster
+    fn focus(&mut self, index: usize) {
+        if index == self.focused || index >= self.receivers.len() {
+            return;
+        }
+        self.focused = index;
+        self.receiver_settings = self.receivers[index].session.settings().clone();
+        self.zoom.fit();
+    }
 
+    fn close_receiver(&mut self, index: usize) {
+        if index >= self.receivers.len() {
+            return;
+        }
+        self.receivers.remove(index);
+        if index < self.focused || self.focused >= self.receivers.len() {
+            self.focused = self.focused.saturating_sub(1);
+        }
+        if let Some(tile) = self.receivers.get(self.focused) {
+            self.receiver_settings = tile.session.settings().clone();
+        }
+        self.zoom.fit();
+    }
 
-    // Funzione per aggiornare l'interfaccia utente con il video ricevuto
-    pub fn update_video_texture(&mut self, ctx: &egui::Context) {
-        if let Some(receiver) = &self.receiver_channel {
-            if let Ok(image) = receiver.try_recv() {
-                // Se ricevi una nuova immagine dal canale
-                if let Some(texture_handle) = self.texture.as_mut() {
-                    // Usa un riferimento mutabile per la texture
-                    texture_handle.set(image, TextureOptions::LINEAR);
-                }
-            }
+    fn add_receiver(&mut self, tile: ReceiverTile) {
+        self.receivers.push(tile);
+        self.focused = self.receivers.len() - 1;
+        self.zoom.fit();
+        self.grid = true;
+    }
+
+    fn grid_shown(&self) -> bool {
+        self.grid && self.receivers.len() > 1
+    }
+
+    fn stream(&self) -> &SenderStream {
+        &self.streams[self.selected_stream]
+    }
+
+    fn stream_mut(&mut self) -> &mut SenderStream {
+        &mut self.streams[self.selected_stream]
+    }
+
+    //annotations are drawn on the selected stream only, so they do not follow a switch
+    fn select_stream(&mut self, index: usize) {
+        if index == self.selected_stream || index >= self.streams.len() {
+            return;
+        }
+        self.selected_stream = index;
+        self.annotations.clear();
+        self.texture = None;
+    }
+
+    //the new stream starts from the selected one's settings on the next monitor, without audio
+    fn add_stream(&mut self) {
+        if self.streams.len() >= MAX_STREAMS {
+            return;
+        }
+        let monitors = get_monitors();
+        if monitors.is_empty() {
+            return;
+        }
+        //a window source counts as being on the first monitor
+        let current = monitors.iter().position(|m| self.stream().source == CaptureSource::Monitor(m.id())).unwrap_or(0);
+        let next = (current + 1) % monitors.len();
+        //the ports of a removed stream are reused, the others keep theirs
+        let index = (0..MAX_STREAMS).find(|i| self.streams.iter().all(|s| s.ports.index() != *i)).unwrap();
+        let settings = StreamSettings { audio: AudioSettings::default(), ..self.stream().settings.clone() };
+        self.streams.push(SenderStream::new(StreamPorts(index), &monitors[next], settings));
+        self.streams.sort_by_key(|s| s.ports.index());
+        let position = self.streams.iter().position(|s| s.ports.index() == index).unwrap();
+        self.select_stream(position);
+    }
+
+    fn remove_stream(&mut self, index: usize) {
+        if self.streams.len() <= 1 || index >= self.streams.len() {
+            return;
+        }
+        self.streams.remove(index);
+        if index == self.selected_stream {
+            self.annotations.clear();
+            self.texture = None;
+        }
+        if index < self.selected_stream || self.selected_stream >= self.streams.len() {
+            self.selected_stream = self.selected_stream.saturating_sub(1);
         }
     }
 }
+
 impl App for MyApp {
 
     //application main loop
@@ -196,6 +448,26 @@ impl App for MyApp {
             self.monitor_preview = None;
         }
 
+        //stop streaming and receiving when leaving their pages
+        if self.state != Sending && self.state != PortionSelection {
+            for stream in self.streams.iter_mut() {
+                stream.stop();
+            }
+        }
+        //the casters stay connected while adding another one from the receiver page
+        if self.state != State::Connection && self.state != Receiver {
+            self.receivers.clear();
+            self.focused = 0;
+        }
+        if self.state != State::Connection {
+            self.video_area = None;
+            set_pip(ctx, self, false);
+            if self.presentation.active {
+                ctx.send_viewport_cmd(egui::ViewportCommand::Fullscreen(false));
+                self.presentation.active = false;
+            }
+        }
+
         //if terminate key pressed return to main menu
         if self.keys.iter().find(|(k,_,_)| {k == "TERMINATE"}).unwrap().2 {
             if self.state == MainMenu {
@@ -257,35 +529,67 @@ impl App for MyApp {
             _ => {}
         }
 
-        //capture new frame and set it as a texture
-        //after page render to avoid slowing it down
-        if self.state == Sending
-            && !self.keys.iter().find(|(k, _, _)| k == "PAUSE").unwrap().2
-            && self.timer.elapsed() >= WAIT_FRAME
-            && !self.keys.iter().find(|(k,_,_)| {k == "HIDE"}).unwrap().2
+        //the capture threads feed the encoders themselves, here the newest frame of the selected stream goes to the preview
+        if self.state == Sending || self.state == PortionSelection {
+            let hidden = self.keys.iter().find(|(k,_,_)| {k == "HIDE"}).unwrap().2;
+            let paused = self.keys.iter().find(|(k, _, _)| k == "PAUSE").unwrap().2 || hidden;
+            let muted_audio = self.keys.iter().find(|(k, _, _)| k == "MUTE AUDIO").unwrap().2;
+            let muted_mic = self.keys.iter().find(|(k, _, _)| k == "MUTE MIC").unwrap().2;
+
+            //annotations go into the outgoing frames, the preview draws them as an overlay
+            self.annotations.expire();
+            let mut newest = None;
+            for (index, stream) in self.streams.iter_mut().enumerate() {
+                let config = CaptureConfig {
+                    source: stream.source,
+                    crop: stream.crop,
+                    rate: stream.settings.framerate,
+                    paused,
+                    cursor: stream.settings.cursor,
+                };
+                match &stream.worker {
+                    None => {
+                        let repaint_ctx = ctx.clone();
+                        stream.worker = Some(CaptureWorker::start(config, move || repaint_ctx.request_repaint()));
+                    }
+                    Some(worker) => worker.configure(config),
+                }
+                let worker = stream.worker.as_ref().unwrap();
+
+                //the capture thread feeds the encoder, this thread only shows the frames
+                worker.set_input(stream.server.as_ref().map(|s| s.frame_input()));
+                let selected = index == self.selected_stream;
+                worker.set_overlay(if selected { self.annotations.shapes() } else { Vec::new() });
 
-        {
+                //remote input is placed on the captured area of the screen
+                if let Some(server) = stream.server.as_mut() {
+                    if let Some(area) = worker.area() {
+                        server.set_input_area(area);
+                    }
+                    server.send_cursor(&worker.take_cursor());
+                    server.set_audio_muted(muted_audio, muted_mic);
+                }
 
-            if let Ok(img) = capture(&self.monitor) {
-                let mut color_img = ColorImage::from_rgba_unmultiplied([img.width() as usize, img.height() as usize], img.as_raw());
-                if let Some(rect) = self.crop {
-                    color_img = crop_color_image(&color_img, rect.min.x as u32, rect.min.y as u32, rect.width() as u32, rect.height() as u32);
+                for frame in worker.frames() {
+                    if selected {
+                        newest = Some(frame);
+                    } else {
+                        stream.preview = Some(frame);
+                    }
+                }
+            }
+            if self.state == Sending && !hidden {
+                if self.texture.is_none() {
+                    newest = newest.or_else(|| self.stream_mut().preview.take());
+                }
+                if let Some(frame) = newest {
+                    self.texture = Some(ctx.load_texture("image_texture", frame, TextureOptions::LINEAR));
                 }
-                self.texture = Some(ctx.load_texture("image_texture", color_img, TextureOptions::LINEAR));
-                //reset timer that control framerate
-                self.timer = Instant::now();
             }
         }
 
-        //this is used to call update only when a new  frame is needed
-        let t = self.timer.elapsed();
-        ctx.request_repaint_after(
-            if WAIT_FRAME > t {
-                WAIT_FRAME - t
-            } else {
-                Duration::from_secs(0)
-            }
-        );
+        //keeps the stats on screen up to date
+        ctx.request_repaint_after(self.stream().settings.framerate.period());
     }
 
 
@@ -1,43 +1,242 @@
 use gstreamer::prelude::*;
 use gstreamer_app::{AppSink, AppSinkCallbacks};
-use gstreamer_video::{VideoFrame, VideoInfo};
-use eframe::egui::{ColorImage, Color32};
-use std::sync::mpsc;
+use gstreamer_video::{VideoFrameRef, VideoInfo};
+use eframe::egui::{ColorImage, Pos2};
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use gstreamer as gst;
 use gstreamer_video as gst_video;
+use crate::annotation::ViewerMark;
+use crate::audio::{self, AudioPlayer, PlaybackSettings};
+use crate::cursor::expire_clicks;
+use crate::capture::capture::FrameQueue;
+use crate::control::{ControlClient, ControlMessage};
+use crate::remote::InputEvent;
+use crate::streaming::{Codec, SrtMode, SrtSettings, StreamPorts, TcpPorts, Transport, UdpPorts, FEC_PT};
 
-pub fn start_video_receiver(ctx: egui::Context, sender: mpsc::Sender<ColorImage>) -> Result<(), Box<dyn std::error::Error>> {
+// sessioni di rtpbin, le stesse sul caster
+const VIDEO_SESSION: u32 = 0;
+const AUDIO_SESSION: u32 = 1;
+const CODEC_TIMEOUT: Duration = Duration::from_secs(5);
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+// in modalità automatica, se entro questo tempo non arriva nulla via UDP si passa al TCP
+const UDP_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LatencyMode {
+    Lowest,
+    Balanced,
+    Smooth,
+}
+
+impl LatencyMode {
+    pub const ALL: [LatencyMode; 3] = [LatencyMode::Lowest, LatencyMode::Balanced, LatencyMode::Smooth];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            LatencyMode::Lowest => "Lowest latency",
+            LatencyMode::Balanced => "Balanced",
+            LatencyMode::Smooth => "Smooth",
+        }
+    }
+
+    // ms di attesa del jitter buffer per i pacchetti in ritardo o fuori ordine
+    pub fn latency_ms(&self) -> u32 {
+        match self {
+            LatencyMode::Lowest => 20,
+            LatencyMode::Balanced => 100,
+            LatencyMode::Smooth => 400,
+        }
+    }
+
+    // i pacchetti per il FEC devono restare disponibili almeno quanto attende il jitter buffer
+    fn storage_time_ns(&self) -> u64 {
+        (self.latency_ms() as u64 + 100) * 1_000_000
+    }
+
+    // in bassa latenza si scartano i pacchetti troppo vecchi invece di accumulare ritardo
+    fn drop_on_latency(&self) -> bool {
+        *self == LatencyMode::Lowest
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RemoteControl {
+    Off,
+    Requested,
+    Granted,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ReceiverSettings {
+    // quale degli stream del caster guardare, ognuno ha le sue porte
+    pub stream: usize,
+    pub latency: LatencyMode,
+    // None = automatico: UDP con fallback su TCP
+    pub transport: Option<Transport>,
+    // usate solo con il trasporto SRT, in modalità caller si chiama il caster
+    pub srt: SrtSettings,
+    pub playback: PlaybackSettings,
+}
+
+impl Default for ReceiverSettings {
+    fn default() -> Self {
+        ReceiverSettings {
+            stream: 0,
+            latency: LatencyMode::Balanced,
+            transport: None,
+            // il caster ascolta di default, quindi il receiver chiama
+            srt: SrtSettings {
+                mode: SrtMode::Caller,
+                ..SrtSettings::default()
+            },
+            playback: PlaybackSettings::default(),
+        }
+    }
+}
+
+impl ReceiverSettings {
+    pub fn ports(&self) -> StreamPorts {
+        StreamPorts(self.stream)
+    }
+}
+
+// porte su cui gli udpsrc si sono legati: le sceglie il sistema al passaggio a READY e restano
+// della pipeline finché non si ferma, quindi nessun'altra sessione può prenderle nel frattempo
+fn bound_udp_ports(pipeline: &gst::Pipeline) -> Option<UdpPorts> {
+    let port = |name| pipeline.by_name(name).map(|src| src.property::<i32>("port"));
+    Some(UdpPorts {
+        video: port("udpsrc").unwrap_or(0),
+        audio: port("audio_udpsrc").unwrap_or(0),
+        rtcp: port("rtcp_udpsrc")?,
+        audio_rtcp: port("audio_rtcp_udpsrc").unwrap_or(0),
+    })
+}
+
+/// Latest video jitter buffer created by rtpbin, there is a new one for every SSRC the caster uses.
+pub type JitterBufferSlot = Arc<Mutex<Option<gst::Element>>>;
+
+// rtpbin crea jitter buffer e pad di uscita solo quando arriva il primo pacchetto di ogni SSRC;
+// a ogni ricostruzione della pipeline del caster arriva un SSRC nuovo, che prende il posto del vecchio
+fn connect_rtpbin(pipeline: &gst::Pipeline, jitterbuffer: JitterBufferSlot) -> Result<(), Box<dyn Error>> {
+    let rtpbin = pipeline.by_name("rtpbin").ok_or("rtpbin non trovato")?;
+    rtpbin.connect("new-jitterbuffer", false, move |values| {
+        let element = values[1].get::<gst::Element>().ok()?;
+        if values[2].get::<u32>().ok()? == VIDEO_SESSION {
+            *jitterbuffer.lock().unwrap() = Some(element);
+        }
+        None
+    });
+    // con ignore-pt i pacchetti FEC restano nel flusso del video, ma il jitter buffer ne chiede il clock-rate
+    rtpbin.connect("request-pt-map", false, |values| {
+        let session = values[1].get::<u32>().ok()?;
+        let pt = values[2].get::<u32>().ok()?;
+        let (media, clock_rate) = if session == VIDEO_SESSION { ("video", 90_000) } else { ("audio", 48_000) };
+        let caps = gst::Caps::builder("application/x-rtp")
+            .field("media", media)
+            .field("clock-rate", clock_rate)
+            .field("payload", pt as i32)
+            .build();
+        Some(caps.to_value())
+    });
+    let weak = pipeline.downgrade();
+    rtpbin.connect_pad_added(move |_, pad| {
+        let Some(pipeline) = weak.upgrade() else { return };
+        let name = pad.name();
+        let target = if name.starts_with(&format!("recv_rtp_src_{}_", VIDEO_SESSION)) {
+            "video_rtp"
+        } else if name.starts_with(&format!("recv_rtp_src_{}_", AUDIO_SESSION)) {
+            "audio_rtp"
+        } else {
+            return;
+        };
+        let Some(sink) = pipeline.by_name(target).and_then(|e| e.static_pad("sink")) else {
+            return;
+        };
+        if let Some(old) = sink.peer() {
+            let _ = old.unlink(&sink);
+        }
+        if let Err(e) = pad.link(&sink) {
+            eprintln!("Impossibile collegare {} a {}: {:?}", name, target, e);
+        }
+    });
+    Ok(())
+}
+
+/// Where a session gets the stream from: the caster, the transport and, for TCP,
+/// the ports the caster opened for this session.
+#[derive(Clone, Copy, Debug)]
+pub struct RtpSource {
+    pub address: IpAddr,
+    pub transport: Transport,
+    pub tcp: Option<TcpPorts>,
+}
+
+// port=0: ogni sessione ha le sue porte, due sessioni sulla stessa porta mescolerebbero i pacchetti di caster diversi
+fn source_description(source: &RtpSource, codec: Codec) -> Result<String, Box<dyn Error>> {
+    let rtp_caps = format!("media=video,clock-rate=90000,encoding-name={},payload=96", codec.name());
+    Ok(match source.transport {
+        Transport::Udp => format!("udpsrc name=udpsrc port=0 reuse=false caps=\"application/x-rtp,{}\"", rtp_caps),
+        Transport::Tcp => format!(
+            "tcpclientsrc host={} port={} ! application/x-rtp-stream,{} ! rtpstreamdepay",
+            source.address,
+            source.tcp.ok_or("Il caster non ha comunicato le porte TCP")?.video,
+            rtp_caps
+        ),
+        // uri, latenza e passphrase vengono impostati dopo il parsing
+        Transport::Srt => format!("srtsrc name=srtsrc ! application/x-rtp,{}", rtp_caps),
+    })
+}
+
+pub fn start_video_receiver(ctx: egui::Context, source: RtpSource, codec: Codec, with_audio: bool, settings: &ReceiverSettings, frames: Arc<FrameQueue>) -> Result<(gst::Pipeline, JitterBufferSlot), Box<dyn Error>> {
     // Inizializza GStreamer
     gst::init()?;
 
-    // Utilizza una pipeline ottimizzata con meno buffering
-  /*  let pipeline = gst::parse_launch(
-        "udpsrc port=5000 caps=\"application/x-rtp,media=video,encoding-name=H264,payload=96\" \
-        ! rtph264depay ! decodebin ! videoconvert ! videoscale ! video/x-raw,format=RGB,width=640,height=360 \
-        ! appsink name=videosink"
-    )?;
-*/
-    let ip="192.168.216.246";
-    // Crea la pipeline utilizzando il parametro ip per il bind della sorgente UDP
+    // Crea la pipeline con il depayloader del codec annunciato dal caster: i jitter buffer di audio e video
+    // stanno nello stesso rtpbin, che con i sender report RTCP li allinea sull'orologio del caster.
+    // ignore-pt: il FEC viaggia con un altro payload type ma deve arrivare al decoder FEC nello stesso flusso
     let pipeline_str = format!(
-        "udpsrc address={} port=5000 caps=\"application/x-rtp,media=video,encoding-name=H264,payload=96\" \
-         ! rtph264depay ! decodebin ! videoconvert ! videoscale ! video/x-raw,format=RGB,width=640,height=360 \
-         ! appsink name=videosink",
-        ip
+        "rtpbin name=rtpbin latency={} drop-on-latency={} do-lost=true ignore-pt=true \
+         {} {}! rtpbin.recv_rtp_sink_{} \
+         udpsrc name=rtcp_udpsrc port=0 reuse=false caps=\"application/x-rtcp\" ! rtpbin.recv_rtcp_sink_{} \
+         identity name=video_rtp {}! {} ! decodebin ! videoconvert ! video/x-raw,format=RGBA \
+         ! appsink name=videosink max-buffers=1 drop=true{}",
+        settings.latency.latency_ms(),
+        settings.latency.drop_on_latency(),
+        source_description(&source, codec)?,
+        fec_storage_description(settings),
+        VIDEO_SESSION,
+        VIDEO_SESSION,
+        fec_decoder_description(),
+        codec.depayloader(),
+        if with_audio { audio::receiver_description(&source) } else { String::new() }
     );
     let pipeline = gst::parse_launch(&pipeline_str)?;
 
     let pipeline = pipeline
         .downcast::<gst::Pipeline>()
         .map_err(|_| "Failed to downcast pipeline to gst::Pipeline")?;
+    let jitterbuffer = JitterBufferSlot::default();
+    connect_rtpbin(&pipeline, jitterbuffer.clone())?;
+
+    if let Some(srtsrc) = pipeline.by_name("srtsrc") {
+        settings.srt.configure(&srtsrc, &source.address.to_string(), settings.ports().srt())?;
+    }
+
+    // il decoder FEC ricostruisce i pacchetti persi da quelli conservati in rtpstorage
+    if let (Some(storage), Some(fec)) = (pipeline.by_name("storage"), pipeline.by_name("fec")) {
+        let internal_storage = storage.property::<gst::glib::Object>("internal-storage");
+        fec.set_property("storage", &internal_storage);
+    }
 
     let appsink = pipeline.by_name("videosink")
         .ok_or("Cannot find appsink element")?
         .downcast::<AppSink>()
         .map_err(|_| "Cannot cast element to AppSink")?;
 
-    let sender_clone = sender.clone();
-
     appsink.set_callbacks(
         AppSinkCallbacks::builder()
             .new_sample(move |sink| {
@@ -47,62 +246,23 @@ pub fn start_video_receiver(ctx: egui::Context, sender: mpsc::Sender<ColorImage>
 
                 // Ottieni i caps dal sample e crea il VideoInfo
                 let caps = sample.caps().ok_or(gst::FlowError::Error)?;
-                let info = VideoInfo::from_caps(&caps).map_err(|_| gst::FlowError::Error)?;
+                let info = VideoInfo::from_caps(caps).map_err(|_| gst::FlowError::Error)?;
+                if info.format() != gst_video::VideoFormat::Rgba {
+                    eprintln!("Formato video non supportato: {:?}", info.format());
+                    return Err(gst::FlowError::Error);
+                }
 
-                // Copia il buffer per ottenere un buffer "owned"
-                let owned_buffer = buffer_ref.copy();
-                let video_frame = VideoFrame::from_buffer_readable(owned_buffer, &info)
+                // Mappa il buffer in sola lettura, senza copiarlo
+                let video_frame = VideoFrameRef::from_buffer_ref_readable(buffer_ref, &info)
                     .map_err(|_| gst::FlowError::Error)?;
-
-                let width = info.width() as usize;
-                let height = info.height() as usize;
-
-                // Determina i byte per pixel in base al formato:
-                let format = info.format();
-                let bytes_per_pixel = match format {
-                    gst_video::VideoFormat::Rgb => 3,
-                    gst_video::VideoFormat::Rgba => 4,
-                    _ => {
-                        eprintln!("Formato video non supportato: {:?}", format);
-                        return Err(gst::FlowError::Error);
-                    }
-                };
-
-                // Ottieni i dati del primo piano e lo stride
                 let plane = video_frame.plane_data(0).map_err(|_| gst::FlowError::Error)?;
                 let stride = video_frame.plane_stride()[0] as usize;
 
-                // Prepara il vettore di pixel: ogni pixel sarà un Color32
-                let mut pixels = Vec::with_capacity(width * height);
-
-                // Itera su ogni riga
-                for row in 0..height {
-                    let start = row * stride;
-                    // Leggi esattamente width * bytes_per_pixel byte per la riga
-                    let row_slice = &plane[start..start + width * bytes_per_pixel];
-                    if bytes_per_pixel == 3 {
-                        // Se il formato è RGB, usa i 3 byte nell'ordine (R, G, B)
-                        for chunk in row_slice.chunks_exact(3) {
-                            pixels.push(Color32::from_rgb(chunk[0], chunk[1], chunk[2]));
-                        }
-                    } else if bytes_per_pixel == 4 {
-                        // Se il formato è RGBA, ignora il canale alpha
-                        for chunk in row_slice.chunks_exact(4) {
-                            pixels.push(Color32::from_rgb(chunk[0], chunk[1], chunk[2]));
-                        }
-                    }
-                }
-
-                // Crea il ColorImage
-                let image = ColorImage {
-                    size: [width, height],
-                    pixels,
-                };
+                let image = rgba_to_color_image(plane, info.width() as usize, info.height() as usize, stride);
 
-                // Invia l'immagine al thread principale e richiedi il repaint dell'UI
-                if sender_clone.send(image).is_ok() {
-                    ctx.request_repaint();
-                }
+                // Consegna l'immagine all'UI: se il frame precedente non è ancora stato mostrato viene scartato
+                frames.push(image);
+                ctx.request_repaint();
 
                 Ok(gst::FlowSuccess::Ok)
             })
@@ -112,5 +272,588 @@ pub fn start_video_receiver(ctx: egui::Context, sender: mpsc::Sender<ColorImage>
     pipeline.set_state(gst::State::Playing)?;
 
     // La pipeline continua a girare in background
-    Ok(())
+    Ok((pipeline, jitterbuffer))
+}
+
+// Il FEC è opzionale sul caster: senza pacchetti di correzione questi elementi lasciano passare il video
+fn fec_available() -> bool {
+    gst::ElementFactory::find("rtpstorage").is_some() && gst::ElementFactory::find("rtpulpfecdec").is_some()
+}
+
+fn fec_storage_description(settings: &ReceiverSettings) -> String {
+    if !fec_available() {
+        return String::new();
+    }
+    format!("! rtpstorage name=storage size-time={} ", settings.latency.storage_time_ns())
+}
+
+fn fec_decoder_description() -> String {
+    if !fec_available() {
+        return String::new();
+    }
+    format!("! rtpulpfecdec name=fec pt={} ", FEC_PT)
+}
+
+// Il video decodificato è opaco, quindi i byte RGBA sono già premoltiplicati e si copiano in blocco
+pub fn rgba_to_color_image(plane: &[u8], width: usize, height: usize, stride: usize) -> ColorImage {
+    let row_bytes = width * 4;
+    if stride == row_bytes {
+        return ColorImage::from_rgba_premultiplied([width, height], &plane[..row_bytes * height]);
+    }
+    let mut rgba = Vec::with_capacity(row_bytes * height);
+    for row in plane.chunks(stride).take(height) {
+        rgba.extend_from_slice(&row[..row_bytes]);
+    }
+    ColorImage::from_rgba_premultiplied([width, height], &rgba)
+}
+
+fn reports_video_corruption(msg: &gst::Message, codec: Codec) -> bool {
+    let Some(factory) = msg.src().and_then(|src| src.downcast_ref::<gst::Element>()).and_then(|e| e.factory()) else {
+        return false;
+    };
+    let klass = factory.klass();
+    factory.name() == codec.depayloader() || (klass.contains("Decoder") && klass.contains("Video"))
+}
+
+fn viewer_name() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "viewer".to_string())
+}
+
+/// Control connection with the caster's announcements, ready to start the pipeline.
+pub struct Handshake {
+    address: IpAddr,
+    settings: ReceiverSettings,
+    control: ControlClient,
+    codec: Codec,
+    audio: bool,
+    tcp: Option<TcpPorts>,
+}
+
+impl Handshake {
+    fn run(address: IpAddr, settings: ReceiverSettings) -> Result<Handshake, Box<dyn Error>> {
+        let control = ControlClient::connect(address, settings.ports().control())?;
+        control.send(&ControlMessage::Hello(viewer_name()))?;
+        // col TCP il caster risponde con le porte che ha aperto solo per questa sessione
+        let transport = settings.transport.unwrap_or(Transport::Udp);
+        if transport != Transport::Udp {
+            control.send(&ControlMessage::Transport(transport))?;
+        }
+
+        // il caster annuncia audio e codec appena si connette
+        let mut audio = false;
+        let mut codec = None;
+        let mut tcp = None;
+        while codec.is_none() || (transport == Transport::Tcp && tcp.is_none()) {
+            match control.recv_timeout(CODEC_TIMEOUT) {
+                Some(ControlMessage::Codec(c)) => codec = Some(c),
+                Some(ControlMessage::Audio(a)) => audio = a,
+                Some(ControlMessage::TcpPorts(ports)) => tcp = Some(ports),
+                Some(_) => continue,
+                None if codec.is_some() => return Err("Il caster non ha aperto le porte TCP".into()),
+                None => return Err("Il caster non ha annunciato il codec".into()),
+            }
+        }
+        let codec = codec.ok_or("Il caster non ha annunciato il codec")?;
+        Ok(Handshake { address, settings, control, codec, audio, tcp })
+    }
+}
+
+/// Connection in progress: the handshake runs on its own thread so the ui keeps drawing.
+pub struct PendingSession {
+    address: IpAddr,
+    result: mpsc::Receiver<Result<Handshake, String>>,
+}
+
+impl PendingSession {
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    /// None while the caster has not answered yet.
+    pub fn poll(&self) -> Option<Result<Handshake, String>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err("Connessione interrotta".to_string())),
+        }
+    }
+}
+
+/// Connection to a single caster: control channel plus the video pipeline built for its codec.
+pub struct ReceiverSession {
+    pipeline: gst::Pipeline,
+    jitterbuffer: JitterBufferSlot,
+    address: IpAddr,
+    codec: Codec,
+    transport: Transport,
+    // porte TCP ricevute e non ancora usate: ogni pipeline TCP ne consuma un paio
+    tcp: Option<TcpPorts>,
+    // si è chiesto il TCP al caster, la pipeline si ricostruisce quando arrivano le porte
+    awaiting_tcp: bool,
+    audio: bool,
+    started: Instant,
+    settings: ReceiverSettings,
+    control: ControlClient,
+    ctx: egui::Context,
+    frames: Arc<FrameQueue>,
+    lost_packets: u64,
+    last_keyframe_request: Option<Instant>,
+    remote_control: RemoteControl,
+    audio_player: AudioPlayer,
+    // cursore del caster quando arriva come metadato, con i click ancora da animare
+    cursor: Option<Pos2>,
+    clicks: Vec<(Pos2, Instant)>,
+}
+
+impl ReceiverSession {
+    /// Starts the handshake with the caster; the ui is repainted when it is over.
+    pub fn connect(ctx: egui::Context, address: IpAddr, settings: ReceiverSettings) -> PendingSession {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(Handshake::run(address, settings).map_err(|e| e.to_string()));
+            ctx.request_repaint();
+        });
+        PendingSession { address, result: rx }
+    }
+
+    /// Builds the pipeline of a finished handshake. It stays on the ui thread, the SDL audio device can't be moved.
+    pub fn start(ctx: egui::Context, handshake: Handshake, frames: Arc<FrameQueue>) -> Result<ReceiverSession, Box<dyn Error>> {
+        let Handshake { address, settings, control, codec, audio, tcp } = handshake;
+        let transport = settings.transport.unwrap_or(Transport::Udp);
+        let (pipeline, jitterbuffer) = start_video_receiver(ctx.clone(), RtpSource { address, transport, tcp }, codec, audio, &settings, frames.clone())?;
+        let mut session = ReceiverSession {
+            pipeline,
+            jitterbuffer,
+            address,
+            codec,
+            transport,
+            tcp: None,
+            awaiting_tcp: false,
+            audio,
+            started: Instant::now(),
+            settings,
+            control,
+            ctx,
+            frames,
+            lost_packets: 0,
+            last_keyframe_request: None,
+            remote_control: RemoteControl::Off,
+            audio_player: AudioPlayer::default(),
+            cursor: None,
+            clicks: Vec::new(),
+        };
+        session.audio_player.attach(&session.pipeline, &session.settings.playback);
+        session.announce_udp_ports();
+        // senza keyframe il decoder non può mostrare nulla fino al prossimo intervallo
+        session.request_keyframe();
+        Ok(session)
+    }
+
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn settings(&self) -> &ReceiverSettings {
+        &self.settings
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Whether the caster sends audio and this pipeline plays it.
+    pub fn has_audio(&self) -> bool {
+        self.pipeline.by_name("audiosink").is_some()
+    }
+
+    /// Peak level of the received audio in dB, for the meter.
+    pub fn audio_level_db(&self) -> f64 {
+        self.audio_player.level_db()
+    }
+
+    /// Frames overwritten before the UI could show them.
+    pub fn dropped_frames(&self) -> usize {
+        self.frames.dropped()
+    }
+
+    pub fn lost_packets(&self) -> u64 {
+        self.lost_packets
+    }
+
+    /// Packets rebuilt by the FEC decoder and packets it could not recover.
+    pub fn fec_stats(&self) -> Option<(u32, u32)> {
+        let fec = self.pipeline.by_name("fec")?;
+        Some((fec.property::<u32>("recovered"), fec.property::<u32>("unrecovered")))
+    }
+
+    /// Caster's cursor sent as metadata, normalised on the frame.
+    pub fn cursor(&self) -> Option<Pos2> {
+        self.cursor
+    }
+
+    pub fn clicks(&self) -> &[(Pos2, Instant)] {
+        &self.clicks
+    }
+
+    pub fn remote_control(&self) -> RemoteControl {
+        self.remote_control
+    }
+
+    /// Asks the caster for control of its mouse and keyboard; the caster has to approve it.
+    pub fn request_control(&mut self) {
+        if self.remote_control != RemoteControl::Off {
+            return;
+        }
+        match self.control.send(&ControlMessage::ControlRequest) {
+            Ok(_) => self.remote_control = RemoteControl::Requested,
+            Err(e) => eprintln!("Richiesta di controllo fallita: {}", e),
+        }
+    }
+
+    pub fn release_control(&mut self) {
+        if self.remote_control == RemoteControl::Off {
+            return;
+        }
+        let _ = self.control.send(&ControlMessage::ControlRelease);
+        self.remote_control = RemoteControl::Off;
+    }
+
+    /// Forwards an input event, dropped unless the caster granted the control.
+    pub fn send_input(&self, event: InputEvent) {
+        if self.remote_control != RemoteControl::Granted {
+            return;
+        }
+        if let Err(e) = self.control.send(&ControlMessage::Input(event)) {
+            eprintln!("Invio dell'input fallito: {}", e);
+        }
+    }
+
+    /// Sends a ping or stroke drawn over the video to the caster.
+    pub fn send_mark(&self, mark: ViewerMark) {
+        if let Err(e) = self.control.send(&ControlMessage::Mark(mark)) {
+            eprintln!("Invio del segno fallito: {}", e);
+        }
+    }
+
+    /// The jitter buffer latency can be changed without rebuilding the pipeline,
+    /// the transport needs a new one.
+    pub fn apply(&mut self, settings: ReceiverSettings) {
+        // lo stream si sceglie alla connessione, il canale di controllo resta su quello
+        let settings = ReceiverSettings { stream: self.settings.stream, ..settings };
+        let transport = settings.transport.unwrap_or(self.transport);
+        let srt_changed = settings.srt != self.settings.srt;
+        self.settings = settings;
+        if transport != self.transport || (transport == Transport::Srt && srt_changed) {
+            self.rebuild(self.codec, transport);
+            return;
+        }
+        let settings = &self.settings;
+        // rtpbin passa latenza e drop-on-latency ai jitter buffer di audio e video
+        if let Some(rtpbin) = self.pipeline.by_name("rtpbin") {
+            rtpbin.set_property("latency", settings.latency.latency_ms());
+            rtpbin.set_property("drop-on-latency", settings.latency.drop_on_latency());
+        }
+        self.audio_player.apply(&self.pipeline, &self.settings.playback);
+        if let Some(storage) = self.pipeline.by_name("storage") {
+            storage.set_property("size-time", settings.latency.storage_time_ns());
+        }
+    }
+
+    /// Handles control channel messages and pipeline errors, called once per UI frame.
+    pub fn poll(&mut self) {
+        while let Some(msg) = self.control.try_recv() {
+            match msg {
+                ControlMessage::Codec(codec) => {
+                    if codec != self.codec {
+                        println!("Il caster è passato a {}", codec.name());
+                        self.rebuild(codec, self.target_transport());
+                    }
+                }
+                ControlMessage::Audio(audio) => {
+                    if audio != self.audio {
+                        self.audio = audio;
+                        self.rebuild(self.codec, self.target_transport());
+                    }
+                }
+                // risposta a una richiesta di TCP, o porte nuove dopo che il caster ha ricostruito la pipeline
+                ControlMessage::TcpPorts(ports) => {
+                    self.tcp = Some(ports);
+                    if self.target_transport() == Transport::Tcp {
+                        self.rebuild(self.codec, Transport::Tcp);
+                    }
+                }
+                ControlMessage::ControlGrant(granted) => {
+                    self.remote_control = if granted { RemoteControl::Granted } else { RemoteControl::Off };
+                }
+                ControlMessage::Cursor(pos) => self.cursor = pos,
+                ControlMessage::Click(pos) => self.clicks.push((pos, Instant::now())),
+                _ => {}
+            }
+        }
+        expire_clicks(&mut self.clicks);
+
+        // UDP bloccato da un firewall o connessione via port forward: si riprova su TCP
+        if self.settings.transport.is_none()
+            && self.transport == Transport::Udp
+            && !self.awaiting_tcp
+            && self.started.elapsed() >= UDP_TIMEOUT
+            && self.jitterbuffer_stat("num-pushed") == 0
+        {
+            println!("Nessun pacchetto UDP ricevuto, passaggio a TCP");
+            self.rebuild(self.codec, Transport::Tcp);
+        }
+
+        if let Some(bus) = self.pipeline.bus() {
+            let mut corrupted = false;
+            while let Some(msg) = bus.pop() {
+                if self.audio_player.handle_message(&msg) {
+                    continue;
+                }
+                match msg.view() {
+                    gst::MessageView::Error(err) => {
+                        eprintln!("Errore nella ricezione video: {:?}", err);
+                        corrupted = true;
+                    }
+                    // i decoder video e il depayloader segnalano i frame danneggiati come warning,
+                    // gli altri avvisi (audio, rete, sink) non si risolvono con un keyframe
+                    gst::MessageView::Warning(warning) => {
+                        eprintln!("Avviso nella ricezione video: {:?}", warning);
+                        if reports_video_corruption(&msg, self.codec) {
+                            corrupted = true;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if corrupted {
+                self.request_keyframe();
+            }
+        }
+
+        // pacchetti persi: l'immagine resta corrotta fino al prossimo keyframe, quindi lo si chiede subito
+        let lost = self.jitterbuffer_stat("num-lost");
+        if lost > self.lost_packets {
+            self.request_keyframe();
+        }
+        self.lost_packets = lost;
+    }
+
+    fn jitterbuffer_stat(&self, name: &str) -> u64 {
+        self.jitterbuffer.lock().unwrap()
+            .as_ref()
+            .and_then(|jb| jb.property::<gst::Structure>("stats").get::<u64>(name).ok())
+            .unwrap_or(0)
+    }
+
+    // il caster manda in UDP solo alle porte annunciate, che cambiano a ogni pipeline
+    fn announce_udp_ports(&self) {
+        if let Some(udp) = bound_udp_ports(&self.pipeline) {
+            if let Err(e) = self.control.send(&ControlMessage::UdpPorts(udp)) {
+                eprintln!("Impossibile comunicare le porte UDP: {}", e);
+            }
+        }
+    }
+
+    fn request_keyframe(&mut self) {
+        if self.last_keyframe_request.is_some_and(|t| t.elapsed() < KEYFRAME_REQUEST_INTERVAL) {
+            return;
+        }
+        self.last_keyframe_request = Some(Instant::now());
+        if let Err(e) = self.control.send(&ControlMessage::KeyframeRequest) {
+            eprintln!("Richiesta di keyframe fallita: {}", e);
+        }
+    }
+
+    // trasporto della prossima pipeline: quello attuale, o il TCP se se ne aspettano le porte
+    fn target_transport(&self) -> Transport {
+        if self.awaiting_tcp { Transport::Tcp } else { self.transport }
+    }
+
+    fn rebuild(&mut self, codec: Codec, transport: Transport) {
+        let tcp = match (transport, self.tcp.take()) {
+            (Transport::Tcp, Some(tcp)) => Some(tcp),
+            (Transport::Tcp, None) => {
+                // già sul TCP le porte nuove arrivano dopo che il caster ha ricostruito la sua pipeline,
+                // altrimenti si chiedono; intanto resta la pipeline attuale
+                if self.transport != Transport::Tcp && !self.awaiting_tcp {
+                    if let Err(e) = self.control.send(&ControlMessage::Transport(transport)) {
+                        eprintln!("Impossibile comunicare il trasporto: {}", e);
+                        return;
+                    }
+                }
+                self.codec = codec;
+                self.awaiting_tcp = true;
+                return;
+            }
+            _ => None,
+        };
+        let _ = self.pipeline.set_state(gst::State::Null);
+        let source = RtpSource { address: self.address, transport, tcp };
+        match start_video_receiver(self.ctx.clone(), source, codec, self.audio, &self.settings, self.frames.clone()) {
+            Ok((pipeline, jitterbuffer)) => {
+                self.pipeline = pipeline;
+                self.jitterbuffer = jitterbuffer;
+                self.audio_player.attach(&self.pipeline, &self.settings.playback);
+                self.announce_udp_ports();
+                self.codec = codec;
+                // il TCP è stato comunicato quando si sono chieste le porte; per gli altri il caster
+                // smette di inviare UDP a chi non lo usa e chiude i sink TCP rimasti
+                if transport != Transport::Tcp && (transport != self.transport || self.awaiting_tcp) {
+                    if let Err(e) = self.control.send(&ControlMessage::Transport(transport)) {
+                        eprintln!("Impossibile comunicare il trasporto: {}", e);
+                    }
+                }
+                self.transport = transport;
+                self.awaiting_tcp = false;
+                self.started = Instant::now();
+                self.lost_packets = 0;
+                self.last_keyframe_request = None;
+                self.request_keyframe();
+            }
+            Err(e) => {
+                eprintln!("Errore nella ricostruzione della pipeline: {:?}", e);
+                let _ = self.pipeline.set_state(gst::State::Playing);
+            }
+        }
+    }
+}
+
+impl Drop for ReceiverSession {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+        // l'ultimo frame non deve comparire nella prossima sessione
+        self.frames.drain();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eframe::egui::{Color32, TextureOptions};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    const WIDTH: usize = 1920;
+    const HEIGHT: usize = 1080;
+    const FRAMES: u32 = 30;
+
+    fn plane(stride: usize) -> Vec<u8> {
+        (0..stride * HEIGHT).map(|i| (i % 251) as u8).collect()
+    }
+
+    // ogni pixel viene dalla sua riga anche quando le righe hanno il padding, che non entra nell'immagine
+    #[test]
+    fn rgba_conversion_skips_row_padding() {
+        let (width, height) = (3, 2);
+        for stride in [width * 4, width * 4 + 8] {
+            let plane: Vec<u8> = (0..stride * height).map(|i| i as u8).collect();
+            let image = rgba_to_color_image(&plane, width, height, stride);
+            assert_eq!(image.size, [width, height]);
+            for y in 0..height {
+                for x in 0..width {
+                    let p = &plane[y * stride + x * 4..][..4];
+                    assert_eq!(image.pixels[y * width + x], Color32::from_rgba_premultiplied(p[0], p[1], p[2], p[3]));
+                }
+            }
+        }
+    }
+
+    // misure, non verifiche: dipendono dalla macchina. cargo test --release -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn rgba_conversion_cost_at_1080p() {
+        for stride in [WIDTH * 4, WIDTH * 4 + 64] {
+            let plane = plane(stride);
+            let start = Instant::now();
+            for _ in 0..FRAMES {
+                rgba_to_color_image(&plane, WIDTH, HEIGHT, stride);
+            }
+            eprintln!("rgba_to_color_image 1920x1080, stride {}: {:?} per frame", stride, start.elapsed() / FRAMES);
+        }
+    }
+
+    // stesso percorso di update_receiver_textures: la texture si alloca una volta e poi si aggiorna
+    #[test]
+    #[ignore]
+    fn texture_update_cost_at_1080p() {
+        let ctx = egui::Context::default();
+        let frames: Vec<ColorImage> = (0..FRAMES).map(|_| rgba_to_color_image(&plane(WIDTH * 4), WIDTH, HEIGHT, WIDTH * 4)).collect();
+        let mut frames = frames.into_iter();
+        let start = Instant::now();
+        let mut texture = ctx.load_texture("video", frames.next().unwrap(), TextureOptions::LINEAR);
+        for image in frames {
+            texture.set(image, TextureOptions::LINEAR);
+        }
+        eprintln!("texture 1920x1080: {:?} per frame", start.elapsed() / FRAMES);
+    }
+
+    const FEC_TEST_FRAMES: usize = 150;
+    const VIDEO_PT: u8 = 96;
+    // un pacchetto video perso ogni DROP_EVERY, i pacchetti FEC arrivano tutti
+    const DROP_EVERY: usize = 10;
+
+    // caster di prova e receiver vero collegati in loopback: rtpulpfecdec deve ricostruire
+    // i pacchetti persi e i frame devono continuare a uscire dal decoder.
+    // Servono i plugin VP8 e FEC e la rete: cargo test -- --ignored
+    #[test]
+    #[ignore = "richiede i plugin GStreamer VP8 e FEC e la rete di loopback"]
+    fn fec_recovers_dropped_packets_on_loopback() {
+        gst::init().unwrap();
+        let needed = ["videotestsrc", "vp8enc", "rtpvp8pay", "rtpulpfecenc", "rtpbin", "rtpstorage", "rtpulpfecdec", "vp8dec"];
+        let missing = needed.iter().find(|e| gst::ElementFactory::find(e).is_none());
+        assert!(missing.is_none(), "{:?} non disponibile", missing);
+
+        let settings = ReceiverSettings { latency: LatencyMode::Smooth, ..ReceiverSettings::default() };
+        let frames = Arc::new(FrameQueue::new(FEC_TEST_FRAMES));
+        let source = RtpSource { address: IpAddr::from([127, 0, 0, 1]), transport: Transport::Udp, tcp: None };
+        let (receiver, _) = start_video_receiver(egui::Context::default(), source, Codec::Vp8, false, &settings, frames.clone()).unwrap();
+        let port = bound_udp_ports(&receiver).unwrap().video;
+        let fec = receiver.by_name("fec").unwrap();
+
+        let sender = gst::parse_launch(&format!(
+            "videotestsrc is-live=true num-buffers={} pattern=ball ! video/x-raw,width=320,height=240,framerate=30/1 \
+             ! vp8enc deadline=1 keyframe-max-dist=30 ! rtpvp8pay pt={} mtu=400 \
+             ! rtpulpfecenc pt={} percentage=100 ! identity name=lossy ! udpsink host=127.0.0.1 port={}",
+            FEC_TEST_FRAMES, VIDEO_PT, FEC_PT, port
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let media_packets = AtomicUsize::new(0);
+        sender.by_name("lossy").unwrap().static_pad("src").unwrap().add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            let Some(gst::PadProbeData::Buffer(buffer)) = &info.data else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let Ok(map) = buffer.map_readable() else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let is_media = map.get(1).is_some_and(|b| b & 0x7f == VIDEO_PT);
+            if is_media && media_packets.fetch_add(1, Ordering::Relaxed) % DROP_EVERY == DROP_EVERY - 1 {
+                return gst::PadProbeReturn::Drop;
+            }
+            gst::PadProbeReturn::Ok
+        });
+        sender.set_state(gst::State::Playing).unwrap();
+        let bus = sender.bus().unwrap();
+        let msg = bus.timed_pop_filtered(gst::ClockTime::from_seconds(30), &[gst::MessageType::Eos, gst::MessageType::Error]);
+        let finished = matches!(msg.as_ref().map(|m| m.view()), Some(gst::MessageView::Eos(_)));
+        // il jitter buffer rilascia gli ultimi pacchetti dopo la sua latenza
+        thread::sleep(Duration::from_millis(settings.latency.latency_ms() as u64 + 500));
+
+        let recovered = fec.property::<u32>("recovered");
+        let decoded = frames.drain().len() + frames.dropped();
+        let _ = sender.set_state(gst::State::Null);
+        let _ = receiver.set_state(gst::State::Null);
+        eprintln!("FEC: {} pacchetti ricostruiti, {} frame decodificati su {}", recovered, decoded, FEC_TEST_FRAMES);
+
+        assert!(finished, "il caster di prova non è arrivato alla fine: {:?}", msg);
+        assert!(recovered > 0, "nessun pacchetto ricostruito dal FEC");
+        assert!(decoded > FEC_TEST_FRAMES / 2, "troppo pochi frame decodificati: {}", decoded);
+    }
 }
@@ -0,0 +1,334 @@
+use egui::{Key, Modifiers, PointerButton, Pos2, Rect, Vec2};
+use device_query::{DeviceQuery, DeviceState, Keycode};
+use enigo::{Axis, Button, Coordinate, Direction, Enigo, Keyboard, Mouse, Settings};
+use std::error::Error;
+use xcap::{Monitor, Window};
+
+// Eventi di input inoltrati dal receiver: le posizioni sono normalizzate (0..1) sul frame ricevuto,
+// così non dipendono dalla scala con cui il receiver lo mostra né dal ridimensionamento dell'encoder
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputEvent {
+    Move(f32, f32),
+    Button(PointerButton, bool),
+    // righe di scorrimento, positive verso il basso e verso destra
+    Scroll(f32, f32),
+    Key(Key, bool, Modifiers),
+    Text(String),
+}
+
+fn button_name(button: PointerButton) -> &'static str {
+    match button {
+        PointerButton::Primary => "left",
+        PointerButton::Secondary => "right",
+        PointerButton::Middle => "middle",
+        PointerButton::Extra1 => "back",
+        PointerButton::Extra2 => "forward",
+    }
+}
+
+fn button_from_name(name: &str) -> Option<PointerButton> {
+    [PointerButton::Primary, PointerButton::Secondary, PointerButton::Middle, PointerButton::Extra1, PointerButton::Extra2]
+        .into_iter()
+        .find(|b| button_name(*b) == name)
+}
+
+fn state_name(pressed: bool) -> &'static str {
+    if pressed { "down" } else { "up" }
+}
+
+fn modifiers_to_string(modifiers: Modifiers) -> String {
+    let mut names = Vec::new();
+    if modifiers.ctrl { names.push("ctrl"); }
+    if modifiers.alt { names.push("alt"); }
+    if modifiers.shift { names.push("shift"); }
+    if modifiers.mac_cmd { names.push("cmd"); }
+    if names.is_empty() { "-".to_string() } else { names.join("+") }
+}
+
+fn modifiers_from_string(text: &str) -> Modifiers {
+    let mut modifiers = Modifiers::NONE;
+    for name in text.split('+') {
+        match name {
+            "ctrl" => modifiers.ctrl = true,
+            "alt" => modifiers.alt = true,
+            "shift" => modifiers.shift = true,
+            "cmd" => modifiers.mac_cmd = true,
+            _ => {}
+        }
+    }
+    modifiers.command = modifiers.ctrl || modifiers.mac_cmd;
+    modifiers
+}
+
+impl InputEvent {
+    pub fn to_line(&self) -> String {
+        match self {
+            InputEvent::Move(x, y) => format!("MOVE {} {}", x, y),
+            InputEvent::Button(button, pressed) => format!("BUTTON {} {}", button_name(*button), state_name(*pressed)),
+            InputEvent::Scroll(x, y) => format!("SCROLL {} {}", x, y),
+            InputEvent::Key(key, pressed, modifiers) => format!("KEY {} {} {}", key.name(), state_name(*pressed), modifiers_to_string(*modifiers)),
+            InputEvent::Text(text) => format!("TEXT {}", text),
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<InputEvent> {
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        let mut parts = args.split_whitespace();
+        match command {
+            "MOVE" => Some(InputEvent::Move(parts.next()?.parse().ok()?, parts.next()?.parse().ok()?)),
+            "BUTTON" => Some(InputEvent::Button(button_from_name(parts.next()?)?, parts.next()? == "down")),
+            "SCROLL" => Some(InputEvent::Scroll(parts.next()?.parse().ok()?, parts.next()?.parse().ok()?)),
+            "KEY" => Some(InputEvent::Key(
+                Key::from_name(parts.next()?)?,
+                parts.next()? == "down",
+                modifiers_from_string(parts.next().unwrap_or("-")),
+            )),
+            // il testo può contenere spazi, quindi si prende tutto il resto della riga
+            "TEXT" => Some(InputEvent::Text(args.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Keys that do not produce text: they travel as key events, everything else as text.
+pub fn is_special_key(key: Key) -> bool {
+    // lo spazio arriva anche come testo
+    key != Key::Space && key.name().chars().count() > 1
+}
+
+// tasti senza un carattere corrispondente, i caratteri vengono premuti come Unicode
+fn enigo_key(key: Key) -> Option<enigo::Key> {
+    use enigo::Key as K;
+    Some(match key {
+        Key::ArrowDown => K::DownArrow,
+        Key::ArrowLeft => K::LeftArrow,
+        Key::ArrowRight => K::RightArrow,
+        Key::ArrowUp => K::UpArrow,
+        Key::Escape => K::Escape,
+        Key::Tab => K::Tab,
+        Key::Backspace => K::Backspace,
+        Key::Enter => K::Return,
+        Key::Space => K::Space,
+        Key::Delete => K::Delete,
+        Key::Home => K::Home,
+        Key::End => K::End,
+        Key::PageUp => K::PageUp,
+        Key::PageDown => K::PageDown,
+        Key::F1 => K::F1,
+        Key::F2 => K::F2,
+        Key::F3 => K::F3,
+        Key::F4 => K::F4,
+        Key::F5 => K::F5,
+        Key::F6 => K::F6,
+        Key::F7 => K::F7,
+        Key::F8 => K::F8,
+        Key::F9 => K::F9,
+        Key::F10 => K::F10,
+        Key::F11 => K::F11,
+        Key::F12 => K::F12,
+        _ => {
+            let mut chars = key.name().chars();
+            let c = chars.next()?;
+            if chars.next().is_some() {
+                return None;
+            }
+            K::Unicode(c.to_ascii_lowercase())
+        }
+    })
+}
+
+fn enigo_button(button: PointerButton) -> Button {
+    match button {
+        PointerButton::Primary => Button::Left,
+        PointerButton::Secondary => Button::Right,
+        PointerButton::Middle => Button::Middle,
+        PointerButton::Extra1 => Button::Back,
+        PointerButton::Extra2 => Button::Forward,
+    }
+}
+
+// tasti assegnabili alle hotkey, per leggerli dallo stato globale della tastiera
+fn device_key(key: Key) -> Option<Keycode> {
+    use Keycode as K;
+    Some(match key {
+        Key::ArrowDown => K::Down,
+        Key::ArrowLeft => K::Left,
+        Key::ArrowRight => K::Right,
+        Key::ArrowUp => K::Up,
+        Key::Escape => K::Escape,
+        Key::Tab => K::Tab,
+        Key::Backspace => K::Backspace,
+        Key::Enter => K::Enter,
+        Key::Space => K::Space,
+        Key::Delete => K::Delete,
+        Key::Home => K::Home,
+        Key::End => K::End,
+        Key::PageUp => K::PageUp,
+        Key::PageDown => K::PageDown,
+        Key::F1 => K::F1,
+        Key::F2 => K::F2,
+        Key::F3 => K::F3,
+        Key::F4 => K::F4,
+        Key::F5 => K::F5,
+        Key::F6 => K::F6,
+        Key::F7 => K::F7,
+        Key::F8 => K::F8,
+        Key::F9 => K::F9,
+        Key::F10 => K::F10,
+        Key::F11 => K::F11,
+        Key::F12 => K::F12,
+        Key::A => K::A,
+        Key::B => K::B,
+        Key::C => K::C,
+        Key::D => K::D,
+        Key::E => K::E,
+        Key::F => K::F,
+        Key::G => K::G,
+        Key::H => K::H,
+        Key::I => K::I,
+        Key::J => K::J,
+        Key::K => K::K,
+        Key::L => K::L,
+        Key::M => K::M,
+        Key::N => K::N,
+        Key::O => K::O,
+        Key::P => K::P,
+        Key::Q => K::Q,
+        Key::R => K::R,
+        Key::S => K::S,
+        Key::T => K::T,
+        Key::U => K::U,
+        Key::V => K::V,
+        Key::W => K::W,
+        Key::X => K::X,
+        Key::Y => K::Y,
+        Key::Z => K::Z,
+        Key::Num0 => K::Key0,
+        Key::Num1 => K::Key1,
+        Key::Num2 => K::Key2,
+        Key::Num3 => K::Key3,
+        Key::Num4 => K::Key4,
+        Key::Num5 => K::Key5,
+        Key::Num6 => K::Key6,
+        Key::Num7 => K::Key7,
+        Key::Num8 => K::Key8,
+        Key::Num9 => K::Key9,
+        _ => return None,
+    })
+}
+
+/// Watches a key on the whole desktop: while a receiver drives the caster's mouse and keyboard
+/// the caster's window rarely has focus, so egui would not see the key.
+pub struct GlobalKey {
+    device: Option<DeviceState>,
+    down: bool,
+}
+
+impl Default for GlobalKey {
+    fn default() -> Self {
+        GlobalKey { device: DeviceState::checked_new(), down: false }
+    }
+}
+
+impl GlobalKey {
+    /// True once each time `key` goes down.
+    pub fn pressed(&mut self, key: Key) -> bool {
+        let Some(device) = &self.device else {
+            return false;
+        };
+        let down = device_key(key).is_some_and(|code| device.get_keys().contains(&code));
+        let pressed = down && !self.down;
+        self.down = down;
+        pressed
+    }
+}
+
+/// Screen area shown in the stream: the crop is in captured pixels, the monitor position in
+/// desktop coordinates, so the crop is scaled back when the monitor has a scale factor.
+pub fn screen_area(monitor: &Monitor, crop: Option<Rect>) -> Rect {
+    let origin = Pos2::new(monitor.x() as f32, monitor.y() as f32);
+    let size = Vec2::new(monitor.width() as f32, monitor.height() as f32);
+    cropped_area(Rect::from_min_size(origin, size), monitor.scale_factor(), crop)
+}
+
+/// Same as [`screen_area`] for a captured window, using the scale factor of the monitor it is on.
+pub fn window_area(window: &Window, crop: Option<Rect>) -> Rect {
+    let origin = Pos2::new(window.x() as f32, window.y() as f32);
+    let size = Vec2::new(window.width() as f32, window.height() as f32);
+    cropped_area(Rect::from_min_size(origin, size), window.current_monitor().scale_factor(), crop)
+}
+
+fn cropped_area(full: Rect, scale: f32, crop: Option<Rect>) -> Rect {
+    match crop {
+        Some(crop) => {
+            let scale = scale.max(1.0);
+            Rect::from_min_size(full.min + crop.min.to_vec2() / scale, crop.size() / scale)
+        }
+        None => full,
+    }
+}
+
+/// Injects the remote viewer's input on this machine (XTest on Linux, SendInput on Windows).
+pub struct InputInjector {
+    enigo: Enigo,
+    // frazioni di riga accumulate dagli scroll dei touchpad
+    scroll: Vec2,
+}
+
+impl InputInjector {
+    pub fn new() -> Result<InputInjector, Box<dyn Error>> {
+        let enigo = Enigo::new(&Settings::default()).map_err(|e| format!("Impossibile controllare l'input: {:?}", e))?;
+        Ok(InputInjector { enigo, scroll: Vec2::ZERO })
+    }
+
+    pub fn inject(&mut self, event: &InputEvent, area: Rect) -> Result<(), Box<dyn Error>> {
+        match event {
+            InputEvent::Move(x, y) => {
+                let pos = area.min + area.size() * Vec2::new(x.clamp(0.0, 1.0), y.clamp(0.0, 1.0));
+                self.enigo.move_mouse(pos.x.round() as i32, pos.y.round() as i32, Coordinate::Abs)?;
+            }
+            InputEvent::Button(button, pressed) => {
+                self.enigo.button(enigo_button(*button), if *pressed { Direction::Press } else { Direction::Release })?;
+            }
+            InputEvent::Scroll(x, y) => {
+                self.scroll += Vec2::new(*x, *y);
+                let lines = Vec2::new(self.scroll.x.trunc(), self.scroll.y.trunc());
+                self.scroll -= lines;
+                if lines.x != 0.0 {
+                    self.enigo.scroll(lines.x as i32, Axis::Horizontal)?;
+                }
+                if lines.y != 0.0 {
+                    self.enigo.scroll(lines.y as i32, Axis::Vertical)?;
+                }
+            }
+            InputEvent::Key(key, pressed, modifiers) => {
+                let Some(key) = enigo_key(*key) else {
+                    return Ok(());
+                };
+                let held: Vec<enigo::Key> = [
+                    (modifiers.ctrl, enigo::Key::Control),
+                    (modifiers.alt, enigo::Key::Alt),
+                    (modifiers.shift, enigo::Key::Shift),
+                    (modifiers.mac_cmd, enigo::Key::Meta),
+                ]
+                .into_iter()
+                .filter_map(|(active, key)| active.then_some(key))
+                .collect();
+                // i modificatori avvolgono il tasto: premuti prima, rilasciati dopo
+                if *pressed {
+                    for m in &held {
+                        self.enigo.key(*m, Direction::Press)?;
+                    }
+                    self.enigo.key(key, Direction::Press)?;
+                } else {
+                    self.enigo.key(key, Direction::Release)?;
+                    for m in held.iter().rev() {
+                        self.enigo.key(*m, Direction::Release)?;
+                    }
+                }
+            }
+            InputEvent::Text(text) => self.enigo.text(text)?,
+        }
+        Ok(())
+    }
+}
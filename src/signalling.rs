@@ -0,0 +1,302 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tungstenite::Message;
+
+// Server HTTP + WebSocket per i viewer WebRTC: la pagina su "/" e la segnalazione su "/ws".
+// I messaggi sono testuali, quindi basta un client WebSocket qualsiasi (es. websocat) per provarla senza browser
+pub const SIGNALLING_PORT: u16 = 8080;
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+const MAX_REQUEST_HEAD: usize = 8192;
+const VIEWER_PAGE: &str = include_str!("../assets/viewer.html");
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignallingMessage {
+    /// caster -> browser, SDP offer created by webrtcbin
+    Offer(String),
+    /// browser -> caster, SDP answer
+    Answer(String),
+    /// both directions, ICE candidate with its m-line index
+    Ice(u32, String),
+}
+
+impl SignallingMessage {
+    // come nel canale di controllo il comando sta sulla prima riga, l'SDP occupa le successive
+    pub fn to_text(&self) -> String {
+        match self {
+            SignallingMessage::Offer(sdp) => format!("OFFER\n{}", sdp),
+            SignallingMessage::Answer(sdp) => format!("ANSWER\n{}", sdp),
+            SignallingMessage::Ice(mline, candidate) => format!("ICE {} {}", mline, candidate),
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<SignallingMessage> {
+        let (head, body) = text.split_once('\n').unwrap_or((text, ""));
+        let (command, args) = head.trim().split_once(' ').unwrap_or((head.trim(), ""));
+        match command {
+            "OFFER" => Some(SignallingMessage::Offer(body.to_string())),
+            "ANSWER" => Some(SignallingMessage::Answer(body.to_string())),
+            "ICE" => {
+                let (mline, candidate) = args.split_once(' ')?;
+                Some(SignallingMessage::Ice(mline.parse().ok()?, candidate.to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SignallingEvent {
+    Connected(usize, IpAddr),
+    Message(usize, SignallingMessage),
+    Disconnected(usize),
+}
+
+type Peers = Arc<Mutex<Vec<(usize, mpsc::Sender<String>)>>>;
+
+/// Sends messages to the browsers, cloneable so the webrtcbin callbacks can use it.
+#[derive(Clone)]
+pub struct SignallingSender {
+    peers: Peers,
+}
+
+impl SignallingSender {
+    pub fn send(&self, id: usize, msg: &SignallingMessage) {
+        if let Some((_, tx)) = self.peers.lock().unwrap().iter().find(|(i, _)| *i == id) {
+            let _ = tx.send(msg.to_text());
+        }
+    }
+}
+
+/// Serves the viewer page and relays the WebRTC negotiation of each browser.
+pub struct SignallingServer {
+    sender: SignallingSender,
+    events: mpsc::Receiver<SignallingEvent>,
+    running: Arc<AtomicBool>,
+}
+
+impl SignallingServer {
+    pub fn start(port: u16) -> io::Result<SignallingServer> {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))?;
+        listener.set_nonblocking(true)?;
+
+        let peers: Peers = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let (tx, rx) = mpsc::channel();
+
+        let peers_clone = peers.clone();
+        let running_clone = running.clone();
+        thread::spawn(move || {
+            let mut next_id = 0;
+            while running_clone.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, addr)) => {
+                        let id = next_id;
+                        next_id += 1;
+                        let tx = tx.clone();
+                        let peers = peers_clone.clone();
+                        let running = running_clone.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = handle_connection(stream, id, addr.ip(), &peers, &tx, &running) {
+                                eprintln!("Errore della connessione HTTP {}: {}", addr, e);
+                            }
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+                    Err(e) => {
+                        eprintln!("Errore del server di segnalazione: {}", e);
+                        thread::sleep(ACCEPT_POLL);
+                    }
+                }
+            }
+        });
+
+        Ok(SignallingServer { sender: SignallingSender { peers }, events: rx, running })
+    }
+
+    pub fn sender(&self) -> SignallingSender {
+        self.sender.clone()
+    }
+
+    pub fn try_recv(&self) -> Option<SignallingEvent> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl Drop for SignallingServer {
+    fn drop(&mut self) {
+        // i thread dei browser controllano il flag a ogni timeout di lettura
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+// Legge l'intestazione della richiesta senza consumarla, così tungstenite può rifare l'handshake
+fn peek_request_head(stream: &TcpStream) -> io::Result<String> {
+    let mut buf = vec![0; MAX_REQUEST_HEAD];
+    loop {
+        let n = stream.peek(&mut buf)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let head = String::from_utf8_lossy(&buf[..n]);
+        if let Some(end) = head.find("\r\n\r\n") {
+            return Ok(head[..end].to_string());
+        }
+        if n == buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Intestazione HTTP troppo lunga"));
+        }
+        thread::sleep(ACCEPT_POLL);
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, id: usize, ip: IpAddr, peers: &Peers, events: &mpsc::Sender<SignallingEvent>, running: &AtomicBool) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let head = peek_request_head(&stream)?;
+    let path = head.split_whitespace().nth(1).unwrap_or("/");
+    let upgrade = head.lines().any(|l| l.to_ascii_lowercase().starts_with("upgrade:") && l.to_ascii_lowercase().contains("websocket"));
+
+    if path == "/ws" && upgrade {
+        return handle_websocket(stream, id, ip, peers, events, running);
+    }
+
+    // richiesta HTTP semplice: si scarta l'intestazione e si risponde con la pagina
+    let mut discard = vec![0; head.len() + 4];
+    stream.read_exact(&mut discard)?;
+    let (status, body) = if path == "/" || path == "/index.html" {
+        ("200 OK", VIEWER_PAGE)
+    } else {
+        ("404 Not Found", "Not found")
+    };
+    let content_type = if status.starts_with("200") { "text/html; charset=utf-8" } else { "text/plain" };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    )?;
+    stream.shutdown(Shutdown::Both)
+}
+
+fn handle_websocket(stream: TcpStream, id: usize, ip: IpAddr, peers: &Peers, events: &mpsc::Sender<SignallingEvent>, running: &AtomicBool) -> io::Result<()> {
+    // il timeout permette di alternare lettura dei messaggi del browser e invio di quelli del caster
+    stream.set_read_timeout(Some(ACCEPT_POLL))?;
+    let mut ws = tungstenite::accept(stream).map_err(|e| io::Error::other(e.to_string()))?;
+
+    let (out_tx, out_rx) = mpsc::channel::<String>();
+    peers.lock().unwrap().push((id, out_tx));
+    let _ = events.send(SignallingEvent::Connected(id, ip));
+
+    'session: while running.load(Ordering::Relaxed) {
+        while let Ok(text) = out_rx.try_recv() {
+            if ws.send(Message::Text(text)).is_err() {
+                break 'session;
+            }
+        }
+        match ws.read() {
+            Ok(Message::Text(text)) => match SignallingMessage::parse(&text) {
+                Some(msg) => {
+                    if events.send(SignallingEvent::Message(id, msg)).is_err() {
+                        break;
+                    }
+                }
+                None => eprintln!("Messaggio di segnalazione sconosciuto: {}", text),
+            },
+            Ok(Message::Close(_)) => break,
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(_) => break,
+        }
+    }
+
+    peers.lock().unwrap().retain(|(i, _)| *i != id);
+    let _ = events.send(SignallingEvent::Disconnected(id));
+    let _ = ws.close(None);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // porta fuori da quelle degli stream, così il test gira anche con il caster aperto
+    const TEST_PORT: u16 = 18080;
+    const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn next_event(server: &SignallingServer) -> SignallingEvent {
+        let deadline = Instant::now() + EVENT_TIMEOUT;
+        loop {
+            if let Some(event) = server.try_recv() {
+                return event;
+            }
+            assert!(Instant::now() < deadline, "nessun evento dal server di segnalazione");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn read_text(ws: &mut tungstenite::WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>) -> String {
+        loop {
+            match ws.read().expect("connessione chiusa") {
+                Message::Text(text) => return text.to_string(),
+                _ => continue,
+            }
+        }
+    }
+
+    // client WebSocket senza browser: stessa negoziazione di viewer.html
+    #[test]
+    fn offer_answer_and_ice_exchange() {
+        let server = SignallingServer::start(TEST_PORT).unwrap();
+        let (mut ws, _) = tungstenite::connect(format!("ws://127.0.0.1:{}/ws", TEST_PORT)).unwrap();
+
+        let id = match next_event(&server) {
+            SignallingEvent::Connected(id, ip) => {
+                assert!(ip.is_loopback());
+                id
+            }
+            event => panic!("evento inatteso: {:?}", event),
+        };
+
+        let offer = SignallingMessage::Offer("v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\n".to_string());
+        server.sender().send(id, &offer);
+        assert_eq!(SignallingMessage::parse(&read_text(&mut ws)), Some(offer));
+
+        let candidate = SignallingMessage::Ice(0, "candidate:1 1 UDP 2122252543 192.168.1.2 50000 typ host".to_string());
+        server.sender().send(id, &candidate);
+        assert_eq!(SignallingMessage::parse(&read_text(&mut ws)), Some(candidate));
+
+        let answer = SignallingMessage::Answer("v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\n".to_string());
+        ws.send(Message::Text(answer.to_text().into())).unwrap();
+        match next_event(&server) {
+            SignallingEvent::Message(from, msg) => {
+                assert_eq!(from, id);
+                assert_eq!(msg, answer);
+            }
+            event => panic!("evento inatteso: {:?}", event),
+        }
+
+        ws.send(Message::Text("ICE 0 candidate:2 1 UDP 2122252543 192.168.1.3 50001 typ host".into())).unwrap();
+        match next_event(&server) {
+            SignallingEvent::Message(from, SignallingMessage::Ice(mline, candidate)) => {
+                assert_eq!(from, id);
+                assert_eq!(mline, 0);
+                assert_eq!(candidate, "candidate:2 1 UDP 2122252543 192.168.1.3 50001 typ host");
+            }
+            event => panic!("evento inatteso: {:?}", event),
+        }
+
+        ws.close(None).unwrap();
+        loop {
+            match next_event(&server) {
+                SignallingEvent::Disconnected(from) => {
+                    assert_eq!(from, id);
+                    break;
+                }
+                SignallingEvent::Message(..) => continue,
+                event => panic!("evento inatteso: {:?}", event),
+            }
+        }
+    }
+}
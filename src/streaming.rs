@@ -1,45 +1,1276 @@
 use gstreamer::prelude::*;
 use gstreamer::ElementFactory;
 use std::error::Error;
-use gstreamer_app::gst;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use gstreamer_app::{gst, AppSrc};
+use gstreamer_video::{UpstreamForceKeyUnitEvent, VideoFormat, VideoInfo};
+use gstreamer_sdp as gst_sdp;
+use gstreamer_webrtc as gst_webrtc;
+use egui::{ColorImage, Pos2, Rect};
+use crate::annotation::MarkBoard;
+use crate::audio::{self, AudioSettings};
+use crate::cursor::{CursorMode, CursorSample, CursorSettings};
+use crate::capture::capture::{CaptureRate, FpsCounter};
+use crate::control::{ControlEvent, ControlMessage, ControlServer, CONTROL_PORT};
+use crate::http::FileServer;
+use crate::remote::{InputEvent, InputInjector};
+use crate::signalling::{SignallingEvent, SignallingMessage, SignallingSender, SignallingServer, SIGNALLING_PORT};
 
-pub fn start_server() -> Result<(), Box<dyn std::error::Error>> {
-    // Inizializza GStreamer
-    gst::init()?;
-    let host_ip = "192.168.216.246";  // Cambia con l'IP del server
-    let pipeline_str = format!(
-        "d3d11screencapturesrc ! videoconvert ! x264enc tune=zerolatency bitrate=3000 speed-preset=ultrafast ! \
-    rtph264pay config-interval=1 pt=96 ! udpsink host={} port=5000",
-        host_ip
-    );
-    let pipeline = gst::parse_launch(&pipeline_str)?;
+pub const SRT_PORT: u16 = 5003;
+pub const HLS_PORT: u16 = 8081;
+// il caster può pubblicare più stream, ognuno con le porte di base spostate di STREAM_PORT_STRIDE
+pub const MAX_STREAMS: usize = 4;
+const STREAM_PORT_STRIDE: u16 = 10;
+const HLS_PLAYLIST: &str = "playlist.m3u8";
+// payload type dei pacchetti di correzione ULPFEC, il video usa 96
+pub const FEC_PT: u32 = 122;
+pub const MAX_FEC_PERCENTAGE: u32 = 100;
+// SRT in modalità live porta al massimo 1316 byte per messaggio; si lascia spazio all'intestazione
+// dei pacchetti FEC (10 + 4 byte), che si attivano senza ricostruire la pipeline
+const SRT_MAX_PAYLOAD: u32 = 1316;
+const FEC_HEADER_SIZE: u32 = 14;
+// al massimo un keyframe forzato per intervallo, le richieste in eccesso vengono accorpate
+const KEYFRAME_MIN_INTERVAL: Duration = Duration::from_secs(1);
 
-    let pipeline = pipeline
-        .downcast::<gst::Pipeline>()
-        .expect("La pipeline non è valida");
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Codec {
+    H264,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 4] = [Codec::H264, Codec::Vp8, Codec::Vp9, Codec::Av1];
+
+    // usato anche come encoding-name nei caps RTP
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::H264 => "H264",
+            Codec::Vp8 => "VP8",
+            Codec::Vp9 => "VP9",
+            Codec::Av1 => "AV1",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        Codec::ALL.into_iter().find(|c| c.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    // encoder in ordine di preferenza: prima quelli hardware, in fondo i software
+    fn encoders(&self) -> &'static [&'static str] {
+        match self {
+            Codec::H264 => &["nvh264enc", "vaapih264enc", "qsvh264enc", "mfh264enc", "x264enc", "openh264enc"],
+            Codec::Vp8 => &["vaapivp8enc", "vp8enc"],
+            Codec::Vp9 => &["vaapivp9enc", "qsvvp9enc", "vp9enc"],
+            Codec::Av1 => &["nvav1enc", "qsvav1enc", "svtav1enc", "av1enc", "rav1enc"],
+        }
+    }
+
+    fn payloader(&self) -> &'static str {
+        match self {
+            Codec::H264 => "rtph264pay",
+            Codec::Vp8 => "rtpvp8pay",
+            Codec::Vp9 => "rtpvp9pay",
+            Codec::Av1 => "rtpav1pay",
+        }
+    }
+
+    pub fn depayloader(&self) -> &'static str {
+        match self {
+            Codec::H264 => "rtph264depay",
+            Codec::Vp8 => "rtpvp8depay",
+            Codec::Vp9 => "rtpvp9depay",
+            Codec::Av1 => "rtpav1depay",
+        }
+    }
+
+    /// Encoders for this codec that are installed, probed from the GStreamer registry.
+    pub fn available_encoders(&self) -> Vec<&'static str> {
+        if ElementFactory::find(self.payloader()).is_none() {
+            return Vec::new();
+        }
+        self.encoders().iter().copied().filter(|e| ElementFactory::find(e).is_some()).collect()
+    }
+}
+
+/// Codecs that have at least one usable encoder on this machine.
+pub fn available_codecs() -> Vec<Codec> {
+    if gst::init().is_err() {
+        return Vec::new();
+    }
+    Codec::ALL.into_iter().filter(|c| !c.available_encoders().is_empty()).collect()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Srt,
+}
+
+impl Transport {
+    pub const ALL: [Transport; 3] = [Transport::Udp, Transport::Tcp, Transport::Srt];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transport::Udp => "UDP",
+            Transport::Tcp => "TCP",
+            Transport::Srt => "SRT",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Transport> {
+        Transport::ALL.into_iter().find(|t| t.name().eq_ignore_ascii_case(name.trim()))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SrtMode {
+    Listener,
+    Caller,
+}
+
+impl SrtMode {
+    pub const ALL: [SrtMode; 2] = [SrtMode::Listener, SrtMode::Caller];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SrtMode::Listener => "listener",
+            SrtMode::Caller => "caller",
+        }
+    }
+}
+
+/// Ports of one of the caster's streams; stream 0 uses the base ports.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct StreamPorts(pub usize);
+
+impl StreamPorts {
+    fn offset(&self) -> u16 {
+        self.0 as u16 * STREAM_PORT_STRIDE
+    }
+
+    pub fn index(&self) -> usize {
+        self.0
+    }
+
+    pub fn srt(&self) -> u16 {
+        SRT_PORT + self.offset()
+    }
+
+    pub fn control(&self) -> u16 {
+        CONTROL_PORT + self.offset()
+    }
+
+    pub fn signalling(&self) -> u16 {
+        SIGNALLING_PORT + self.offset()
+    }
+
+    pub fn hls(&self) -> u16 {
+        HLS_PORT + self.offset()
+    }
+}
+
+/// Local UDP ports of a receiver: each session has its own, so two sessions never share a socket.
+/// A port is 0 when the receiver has no such branch, e.g. no RTP over UDP on TCP or no audio.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UdpPorts {
+    pub video: i32,
+    pub audio: i32,
+    // sender report RTCP di ogni flusso, arrivano in UDP qualunque sia il trasporto dell'RTP
+    pub rtcp: i32,
+    pub audio_rtcp: i32,
+}
+
+/// Ports the caster opened for a single TCP receiver; audio is 0 when the stream has none.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TcpPorts {
+    pub video: i32,
+    pub audio: i32,
+}
+
+// Stesse impostazioni per srtsink e srtsrc: una parte ascolta, l'altra chiama
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SrtSettings {
+    pub mode: SrtMode,
+    // indirizzo da chiamare in modalità caller
+    pub peer: String,
+    pub latency_ms: u32,
+    // vuota = nessuna cifratura, altrimenti almeno 10 caratteri
+    pub passphrase: String,
+}
+
+impl Default for SrtSettings {
+    fn default() -> Self {
+        SrtSettings {
+            mode: SrtMode::Listener,
+            peer: String::new(),
+            latency_ms: 120,
+            passphrase: String::new(),
+        }
+    }
+}
+
+impl SrtSettings {
+    pub const MIN_PASSPHRASE_LEN: usize = 10;
+
+    /// Sets uri, latency and passphrase on an srtsink or srtsrc.
+    pub fn configure(&self, element: &gst::Element, peer: &str, port: u16) -> Result<(), Box<dyn Error>> {
+        let uri = match self.mode {
+            SrtMode::Listener => format!("srt://:{}?mode=listener", port),
+            SrtMode::Caller => format!("srt://{}:{}?mode=caller", peer, port),
+        };
+        element.set_property("uri", &uri);
+        element.set_property("latency", self.latency_ms as i32);
+        if !self.passphrase.is_empty() {
+            if self.passphrase.len() < Self::MIN_PASSPHRASE_LEN {
+                return Err(format!("La passphrase SRT deve avere almeno {} caratteri", Self::MIN_PASSPHRASE_LEN).into());
+            }
+            element.set_property("passphrase", &self.passphrase);
+        }
+        Ok(())
+    }
+}
+
+// Uscita HLS per il pubblico numeroso: latenza di qualche segmento, ma ogni viewer è solo un client HTTP
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HlsSettings {
+    pub segment_seconds: u32,
+    // segmenti elencati nella playlist
+    pub playlist_length: u32,
+}
+
+impl Default for HlsSettings {
+    fn default() -> Self {
+        HlsSettings { segment_seconds: 4, playlist_length: 6 }
+    }
+}
+
+impl HlsSettings {
+    pub const MAX_SEGMENT_SECONDS: u32 = 20;
+    pub const MAX_PLAYLIST_LENGTH: u32 = 30;
+
+    /// Folder with the segments and the playlist of a stream, emptied at every start.
+    pub fn directory(ports: StreamPorts) -> PathBuf {
+        match ports.index() {
+            0 => std::env::temp_dir().join("stream_hls"),
+            index => std::env::temp_dir().join(format!("stream_hls_{}", index)),
+        }
+    }
+
+    // i percorsi si impostano dopo il parsing con configure: le "\" di Windows verrebbero interpretate come escape
+    fn description(&self, codec: Codec, encoder: &str, settings: &StreamSettings) -> String {
+        // i vecchi segmenti restano sul disco finché ci sono client che li scaricano
+        let sink = format!(
+            "hlssink2 name=hls target-duration={} playlist-length={} max-files={}",
+            self.segment_seconds,
+            self.playlist_length,
+            self.playlist_length + 2
+        );
+        if !self.needs_raw_branch(codec, encoder, settings) {
+            format!(" encoded. ! queue ! h264parse ! {}", sink)
+        } else {
+            format!(
+                " raw. ! queue ! videoconvert ! video/x-raw,format=I420 ! x264enc tune=zerolatency bitrate={} speed-preset=ultrafast key-int-max={} ! h264parse ! {}",
+                settings.preset.bitrate(),
+                settings.framerate.nominal_fps() * self.segment_seconds,
+                sink
+            )
+        }
+    }
+
+    /// Sets the segment and playlist paths on the hlssink2.
+    fn configure(&self, sink: &gst::Element, ports: StreamPorts) {
+        let dir = HlsSettings::directory(ports);
+        sink.set_property("location", dir.join("segment%05d.ts").to_string_lossy().as_ref());
+        sink.set_property("playlist-location", dir.join(HLS_PLAYLIST).to_string_lossy().as_ref());
+    }
+
+    // i player HLS vogliono H.264 4:2:0: se lo stream principale non lo è serve un encoder dedicato
+    fn needs_raw_branch(&self, codec: Codec, encoder: &str, settings: &StreamSettings) -> bool {
+        codec != Codec::H264 || full_chroma(encoder, settings)
+    }
+}
+
+/// HLS needs hlssink2 from gst-plugins-bad and h264parse.
+pub fn hls_available() -> bool {
+    gst::init().is_ok() && ElementFactory::find("hlssink2").is_some() && ElementFactory::find("h264parse").is_some()
+}
+
+/// The streams HLS cannot carry as they are (other codecs, 4:4:4) are encoded again with x264enc.
+pub fn hls_reencode_available() -> bool {
+    gst::init().is_ok() && ElementFactory::find("x264enc").is_some()
+}
+
+/// Playlist URL for the HLS players on the LAN.
+pub fn hls_url(ports: StreamPorts) -> Option<String> {
+    Some(format!("http://{}:{}/{}", local_ip()?, ports.hls(), HLS_PLAYLIST))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QualityPreset {
+    Text,
+    Video,
+    LowBandwidth,
+}
+
+impl QualityPreset {
+    pub const ALL: [QualityPreset; 3] = [QualityPreset::Text, QualityPreset::Video, QualityPreset::LowBandwidth];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            QualityPreset::Text => "Text/Slides",
+            QualityPreset::Video => "Video",
+            QualityPreset::LowBandwidth => "Low bandwidth",
+        }
+    }
+
+    // framerate proposto quando si sceglie il preset, poi modificabile
+    pub fn framerate(&self) -> u32 {
+        match self {
+            QualityPreset::Text => 10,
+            QualityPreset::Video => 60,
+            QualityPreset::LowBandwidth => 15,
+        }
+    }
+
+    // kbit/s
+    pub fn bitrate(&self) -> u32 {
+        match self {
+            QualityPreset::Text => 6000,
+            QualityPreset::Video => 4000,
+            QualityPreset::LowBandwidth => 800,
+        }
+    }
+
+    fn keyframe_seconds(&self) -> u32 {
+        match self {
+            QualityPreset::Text => 2,
+            QualityPreset::Video => 1,
+            QualityPreset::LowBandwidth => 4,
+        }
+    }
+
+    // None mantiene la risoluzione catturata
+    fn max_width(&self) -> Option<u32> {
+        match self {
+            QualityPreset::LowBandwidth => Some(1280),
+            _ => None,
+        }
+    }
+
+    // il testo piccolo resta leggibile solo senza sottocampionamento del colore
+    fn full_chroma(&self) -> bool {
+        *self == QualityPreset::Text
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StreamSettings {
+    pub codec: Codec,
+    pub preset: QualityPreset,
+    pub framerate: CaptureRate,
+    // overhead di FEC rispetto ai pacchetti video, 0 = disattivata
+    pub fec_percentage: u32,
+    // uscita SRT in parallelo a UDP e TCP
+    pub srt: Option<SrtSettings>,
+    // viewer da browser serviti da webrtcbin, con la pagina su SIGNALLING_PORT
+    pub webrtc: bool,
+    // segmenti HLS serviti su HLS_PORT, in parallelo all'uscita RTP
+    pub hls: Option<HlsSettings>,
+    pub audio: AudioSettings,
+    // il cursore si compone nel frame dal thread di cattura, in modalità metadati va sul canale di controllo
+    pub cursor: CursorSettings,
+}
+
+impl StreamSettings {
+    fn keyframe_interval(&self) -> u32 {
+        self.framerate.nominal_fps() * self.preset.keyframe_seconds()
+    }
+}
+
+// proprietà di ogni encoder, il bitrate è in kbit/s e l'intervallo dei keyframe in frame
+fn encoder_description(encoder: &str, settings: &StreamSettings) -> String {
+    let bitrate = settings.preset.bitrate();
+    let gop = settings.keyframe_interval();
+    let high_quality = settings.preset == QualityPreset::Text;
+    match encoder {
+        "x264enc" => format!(
+            "x264enc tune=zerolatency bitrate={} speed-preset={} key-int-max={}",
+            bitrate, if high_quality { "veryfast" } else { "ultrafast" }, gop
+        ),
+        "openh264enc" => format!("openh264enc bitrate={} complexity=low gop-size={}", bitrate * 1000, gop),
+        "nvh264enc" => format!(
+            "nvh264enc preset={} zerolatency=true bitrate={} gop-size={}",
+            if high_quality { "low-latency-hq" } else { "low-latency-hp" }, bitrate, gop
+        ),
+        "vaapih264enc" | "vaapivp8enc" | "vaapivp9enc" => format!("{} rate-control=cbr bitrate={} keyframe-period={}", encoder, bitrate, gop),
+        "mfh264enc" | "qsvh264enc" | "qsvvp9enc" | "qsvav1enc" => format!("{} bitrate={} gop-size={}", encoder, bitrate, gop),
+        "vp8enc" | "vp9enc" => format!(
+            "{} deadline=1 cpu-used={} end-usage=cbr target-bitrate={} keyframe-max-dist={}",
+            encoder, if high_quality { 4 } else { 8 }, bitrate * 1000, gop
+        ),
+        "svtav1enc" => format!("svtav1enc preset={} target-bitrate={} intra-period-length={}", if high_quality { 10 } else { 12 }, bitrate, gop),
+        "av1enc" => format!("av1enc usage-profile=realtime cpu-used=8 target-bitrate={} keyframe-max-dist={}", bitrate, gop),
+        "rav1enc" => format!("rav1enc speed-preset=10 low-latency=true bitrate={} max-key-frame-interval={}", bitrate * 1000, gop),
+        _ => format!("{} bitrate={}", encoder, bitrate),
+    }
+}
+
+// caps prima dell'encoder: eventuale ridimensionamento e formato colore
+fn raw_caps(encoder: &str, settings: &StreamSettings) -> String {
+    let mut caps = "video/x-raw".to_string();
+    if let Some(width) = settings.preset.max_width() {
+        caps.push_str(&format!(",width={}", width));
+    }
+    if full_chroma(encoder, settings) {
+        caps.push_str(",format=Y444");
+    }
+    caps
+}
+
+// 4:4:4 solo dove l'encoder software lo supporta
+fn full_chroma(encoder: &str, settings: &StreamSettings) -> bool {
+    settings.preset.full_chroma() && (encoder == "x264enc" || encoder == "vp9enc")
+}
+
+fn payloader_description(codec: Codec, mtu: Option<u32>) -> String {
+    let mtu = mtu.map(|mtu| format!(" mtu={}", mtu)).unwrap_or_default();
+    match codec {
+        Codec::H264 => format!("rtph264pay config-interval=1 pt=96{}", mtu),
+        _ => format!("{} pt=96{}", codec.payloader(), mtu),
+    }
+}
+
+// l'encoder FEC resta sempre nella pipeline, così la percentuale si cambia senza ricostruirla
+fn fec_description(percentage: u32) -> String {
+    if ElementFactory::find("rtpulpfecenc").is_some() {
+        format!(" ! rtpulpfecenc name=fec pt={} percentage={}", FEC_PT, percentage)
+    } else {
+        String::new()
+    }
+}
+
+/// WebRTC needs webrtcbin from gst-plugins-bad.
+pub fn webrtc_available() -> bool {
+    gst::init().is_ok() && ElementFactory::find("webrtcbin").is_some()
+}
+
+fn local_ip() -> Option<IpAddr> {
+    let interfaces = if_addrs::get_if_addrs().ok()?;
+    Some(interfaces.iter().find(|i| !i.is_loopback() && i.ip().is_ipv4())?.ip())
+}
+
+/// Address the browsers on the LAN can open to watch the WebRTC stream.
+pub fn webrtc_url(ports: StreamPorts) -> Option<String> {
+    Some(format!("http://{}:{}", local_ip()?, ports.signalling()))
+}
+
+// i browser decodificano solo il 4:2:0: con il preset testo serve un encoder dedicato
+fn webrtc_needs_raw_branch(encoder: &str, settings: &StreamSettings) -> bool {
+    settings.webrtc && full_chroma(encoder, settings)
+}
+
+// caps fissi davanti a webrtcbin, senza i quali l'offerta verrebbe creata prima di conoscere il codec
+fn webrtc_caps(codec: Codec) -> String {
+    format!("application/x-rtp,media=video,encoding-name={},payload=96,clock-rate=90000", codec.name())
+}
+
+// caps dei frame catturati, cambiano con il ritaglio e con il framerate
+fn capture_caps(size: [usize; 2], framerate: CaptureRate) -> Result<gst::Caps, Box<dyn Error>> {
+    let fps = match framerate {
+        CaptureRate::Fps(fps) => gst::Fraction::new(fps as i32, 1),
+        // framerate variabile: i frame arrivano solo quando lo schermo cambia
+        CaptureRate::OnChange => gst::Fraction::new(0, 1),
+    };
+    let info = VideoInfo::builder(VideoFormat::Rgba, size[0] as u32, size[1] as u32)
+        .fps(fps)
+        .build()?;
+    Ok(info.to_caps()?)
+}
+
+fn build_pipeline(settings: &StreamSettings, ports: StreamPorts) -> Result<(gst::Pipeline, &'static str), Box<dyn Error>> {
+    let codec = settings.codec;
+    let encoders = codec.available_encoders();
+    if encoders.is_empty() {
+        return Err(format!("Nessun encoder disponibile per {}", codec.name()).into());
+    }
+
+    // se un encoder hardware non parte si passa al successivo
+    let mut missing_hls_encoder = false;
+    for encoder in encoders {
+        let hls = settings.hls.map(|hls| (hls.description(codec, encoder, settings), hls.needs_raw_branch(codec, encoder, settings)));
+        if hls.as_ref().is_some_and(|(_, raw)| *raw) && !hls_reencode_available() {
+            missing_hls_encoder = true;
+            continue;
+        }
+        // video e audio passano dallo stesso rtpbin: i sender report RTCP legano i timestamp RTP
+        // dei due flussi allo stesso orologio NTP, e il receiver li allinea con quelli
+        let pipeline_str = format!(
+            "rtpbin name=rtpbin \
+             appsrc name=capture is-live=true do-timestamp=true format=time \
+             ! videoconvert ! videoscale ! {}{} ! {} name=encoder ! tee name=encoded \
+             encoded. ! queue ! {}{} ! rtpbin.send_rtp_sink_0 rtpbin.send_rtp_src_0 ! tee name=rtp \
+             rtpbin.send_rtcp_src_0 ! multiudpsink name=rtcpsink sync=false async=false \
+             rtp. ! queue ! multiudpsink name=udpsink{}{}{}{}",
+            raw_caps(encoder, settings),
+            if hls.as_ref().is_some_and(|(_, raw)| *raw) || webrtc_needs_raw_branch(encoder, settings) { " ! tee name=raw ! queue" } else { "" },
+            encoder_description(encoder, settings),
+            // i pacchetti RTP vanno in SRT così come sono, quindi devono stare in un messaggio SRT
+            payloader_description(codec, settings.srt.as_ref().map(|_| SRT_MAX_PAYLOAD - FEC_HEADER_SIZE)),
+            fec_description(settings.fec_percentage),
+            // i pacchetti RTP viaggiano come messaggi SRT, il receiver usa la stessa catena di decodifica
+            if settings.srt.is_some() { " rtp. ! queue ! srtsink name=srtsink wait-for-connection=false sync=false" } else { "" },
+            // i browser non capiscono i pacchetti FEC, quindi hanno un payloader tutto loro;
+            // i webrtcbin dei singoli viewer si agganciano a questo tee quando si collegano
+            if !settings.webrtc {
+                String::new()
+            } else if webrtc_needs_raw_branch(encoder, settings) {
+                format!(
+                    " raw. ! queue ! videoconvert ! video/x-raw,format=I420 ! {} name=webrtc_encoder ! {} ! tee name=webrtc allow-not-linked=true",
+                    encoder_description(encoder, settings),
+                    payloader_description(codec, None)
+                )
+            } else {
+                format!(" encoded. ! queue ! {} ! tee name=webrtc allow-not-linked=true", payloader_description(codec, None))
+            },
+            hls.map(|(description, _)| description).unwrap_or_default(),
+            audio::sender_description(&settings.audio)
+        );
+        let pipeline = match gst::parse_launch(&pipeline_str) {
+            Ok(p) => p.downcast::<gst::Pipeline>().map_err(|_| "La pipeline non è valida")?,
+            Err(e) => {
+                eprintln!("Encoder {} non utilizzabile: {}", encoder, e);
+                continue;
+            }
+        };
+        if let (Some(srt), Some(sink)) = (&settings.srt, pipeline.by_name("srtsink")) {
+            srt.configure(&sink, &srt.peer, ports.srt())?;
+        }
+        if let (Some(hls), Some(sink)) = (&settings.hls, pipeline.by_name("hls")) {
+            hls.configure(&sink, ports);
+        }
+        match pipeline.set_state(gst::State::Playing) {
+            Ok(_) => return Ok((pipeline, encoder)),
+            Err(e) => {
+                eprintln!("Encoder {} non utilizzabile: {:?}", encoder, e);
+                let _ = pipeline.set_state(gst::State::Null);
+            }
+        }
+    }
+    if missing_hls_encoder {
+        return Err(format!("L'uscita HLS di uno stream {} richiede x264enc", codec.name()).into());
+    }
+    Err(format!("Impossibile avviare uno stream {}", codec.name()).into())
+}
+
+// la cartella si svuota a ogni avvio, così la playlist non punta a segmenti di uno stream precedente
+fn start_hls_server(ports: StreamPorts) -> Result<FileServer, Box<dyn Error>> {
+    let dir = HlsSettings::directory(ports);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)?;
+    Ok(FileServer::start(ports.hls(), dir)?)
+}
+
+struct Viewer {
+    id: usize,
+    ip: IpAddr,
+    name: String,
+    transport: Transport,
+    // porte annunciate dal receiver: fino ad allora non gli si manda niente in UDP
+    udp: Option<UdpPorts>,
+}
+
+// browser collegato via WebRTC: queue, caps e webrtcbin in un bin attaccato al tee "webrtc"
+struct WebPeer {
+    id: usize,
+    ip: IpAddr,
+    bin: gst::Bin,
+    tee_pad: gst::Pad,
+}
+
+impl WebPeer {
+    fn add(pipeline: &gst::Pipeline, codec: Codec, id: usize, ip: IpAddr, signalling: SignallingSender) -> Result<WebPeer, Box<dyn Error>> {
+        let tee = pipeline.by_name("webrtc").ok_or("WebRTC non attivo nella pipeline")?;
+        let bin = gst::parse_bin_from_description(
+            &format!("queue leaky=downstream ! {} ! webrtcbin name=webrtcbin bundle-policy=max-bundle", webrtc_caps(codec)),
+            true,
+        )?;
+        let webrtcbin = bin.by_name("webrtcbin").ok_or("webrtcbin non trovato")?;
+
+        // il caster fa l'offerta appena il webrtcbin ha il flusso video collegato
+        let sender = signalling.clone();
+        webrtcbin.connect("on-negotiation-needed", false, move |values| {
+            let webrtcbin = values[0].get::<gst::Element>().ok()?;
+            let element = webrtcbin.clone();
+            let sender = sender.clone();
+            let promise = gst::Promise::with_change_func(move |reply| {
+                let offer = match reply {
+                    Ok(Some(reply)) => reply.get::<gst_webrtc::WebRTCSessionDescription>("offer"),
+                    _ => return,
+                };
+                let Ok(offer) = offer else {
+                    eprintln!("webrtcbin non ha creato l'offerta per il browser {}", id);
+                    return;
+                };
+                element.emit_by_name::<()>("set-local-description", &[&offer, &None::<gst::Promise>]);
+                match offer.sdp().as_text() {
+                    Ok(sdp) => sender.send(id, &SignallingMessage::Offer(sdp)),
+                    Err(e) => eprintln!("SDP non valido: {}", e),
+                }
+            });
+            webrtcbin.emit_by_name::<()>("create-offer", &[&None::<gst::Structure>, &promise]);
+            None
+        });
+        webrtcbin.connect("on-ice-candidate", false, move |values| {
+            let mline = values[1].get::<u32>().ok()?;
+            let candidate = values[2].get::<String>().ok()?;
+            signalling.send(id, &SignallingMessage::Ice(mline, candidate));
+            None
+        });
+
+        pipeline.add(&bin)?;
+        let tee_pad = tee.request_pad_simple("src_%u").ok_or("Impossibile collegare il browser al tee")?;
+        tee_pad.link(&bin.static_pad("sink").ok_or("Il bin WebRTC non ha ingressi")?)?;
+        bin.sync_state_with_parent()?;
+        Ok(WebPeer { id, ip, bin, tee_pad })
+    }
+
+    fn webrtcbin(&self) -> Option<gst::Element> {
+        self.bin.by_name("webrtcbin")
+    }
+
+    fn remove(self, pipeline: &gst::Pipeline) {
+        detach_from_tee(pipeline, self.bin, self.tee_pad);
+    }
+}
+
+// receiver su TCP: per ogni flusso un tcpserversink su una porta scelta dal sistema, comunicata
+// solo a lui sul canale di controllo. Accetta un solo client: per ricollegarsi il receiver chiede
+// porte nuove, così nessuno può agganciarsi allo stream senza essersi registrato
+struct TcpPeer {
+    id: usize,
+    branches: Vec<(gst::Bin, gst::Pad)>,
+}
+
+impl TcpPeer {
+    fn add(pipeline: &gst::Pipeline, id: usize) -> Result<(TcpPeer, TcpPorts), Box<dyn Error>> {
+        let mut peer = TcpPeer { id, branches: Vec::new() };
+        let mut ports = TcpPorts { video: 0, audio: 0 };
+        for (tee, port) in [("rtp", &mut ports.video), ("audio", &mut ports.audio)] {
+            let Some(tee) = pipeline.by_name(tee) else { continue };
+            match add_tcp_branch(pipeline, &tee) {
+                Ok((bin, tee_pad, bound)) => {
+                    peer.branches.push((bin, tee_pad));
+                    *port = bound;
+                }
+                Err(e) => {
+                    peer.remove(pipeline);
+                    return Err(e);
+                }
+            }
+        }
+        Ok((peer, ports))
+    }
+
+    fn remove(self, pipeline: &gst::Pipeline) {
+        for (bin, tee_pad) in self.branches {
+            detach_from_tee(pipeline, bin, tee_pad);
+        }
+    }
+}
+
+fn add_tcp_branch(pipeline: &gst::Pipeline, tee: &gst::Element) -> Result<(gst::Bin, gst::Pad, i32), Box<dyn Error>> {
+    let bin = gst::parse_bin_from_description(
+        "queue ! rtpstreampay ! tcpserversink name=tcpsink host=0.0.0.0 port=0 sync=false async=false",
+        true,
+    )?;
+    let sink = bin.by_name("tcpsink").ok_or("tcpserversink non trovato")?;
+    // il primo client è il receiver a cui è stata mandata la porta, gli altri vengono chiusi subito
+    let accepted = Arc::new(AtomicBool::new(false));
+    sink.connect("client-added", false, move |values| {
+        let sink = values[0].get::<gst::Element>().ok()?;
+        let socket = values[1].get::<gst::glib::Object>().ok()?;
+        if accepted.swap(true, Ordering::SeqCst) {
+            sink.emit_by_name::<()>("remove", &[&socket]);
+        }
+        None
+    });
+
+    pipeline.add(&bin)?;
+    let tee_pad = tee.request_pad_simple("src_%u").ok_or("Impossibile collegare il TCP al tee")?;
+    tee_pad.link(&bin.static_pad("sink").ok_or("Il bin TCP non ha ingressi")?)?;
+    bin.sync_state_with_parent()?;
+    // la porta è assegnata quando il sink apre il socket, al passaggio a READY
+    let port = sink.property::<i32>("current-port");
+    if port <= 0 {
+        detach_from_tee(pipeline, bin, tee_pad);
+        return Err("tcpserversink non ha aperto la porta".into());
+    }
+    Ok((bin, tee_pad, port))
+}
+
+// si stacca dal tee solo quando non ci passano dati, poi il bin si ferma fuori dal thread di streaming
+fn detach_from_tee(pipeline: &gst::Pipeline, bin: gst::Bin, tee_pad: gst::Pad) {
+    let pipeline = pipeline.clone();
+    tee_pad.add_probe(gst::PadProbeType::IDLE, move |pad, _| {
+        if let Some(tee) = pad.parent_element() {
+            tee.release_request_pad(pad);
+        }
+        let pipeline = pipeline.clone();
+        bin.call_async(move |bin| {
+            let _ = bin.set_state(gst::State::Null);
+            let _ = pipeline.remove(bin);
+        });
+        gst::PadProbeReturn::Remove
+    });
+}
+
+/// Entrance of the encoder, shared with the capture thread: frames go straight into the appsrc
+/// of the current pipeline without passing through the ui.
+#[derive(Clone)]
+pub struct FrameInput(Arc<Mutex<FrameInputState>>);
+
+struct FrameInputState {
+    appsrc: Option<AppSrc>,
+    framerate: CaptureRate,
+    // caps impostati sull'appsrc, cambiano con la dimensione dei frame o col framerate
+    caps: Option<([usize; 2], CaptureRate)>,
+    fps: FpsCounter,
+}
+
+impl FrameInput {
+    fn new(pipeline: &gst::Pipeline, framerate: CaptureRate) -> FrameInput {
+        let input = FrameInput(Arc::new(Mutex::new(FrameInputState {
+            appsrc: None,
+            framerate,
+            caps: None,
+            fps: FpsCounter::default(),
+        })));
+        input.reset(pipeline, framerate);
+        input
+    }
+
+    // nuova pipeline: i caps si impostano di nuovo col prossimo frame
+    fn reset(&self, pipeline: &gst::Pipeline, framerate: CaptureRate) {
+        let mut state = self.0.lock().unwrap();
+        state.appsrc = pipeline.by_name("capture").and_then(|e| e.downcast::<AppSrc>().ok());
+        state.framerate = framerate;
+        state.caps = None;
+    }
+
+    fn set_framerate(&self, framerate: CaptureRate) {
+        self.0.lock().unwrap().framerate = framerate;
+    }
+
+    pub fn push(&self, image: &ColorImage) {
+        let mut state = self.0.lock().unwrap();
+        let Some(appsrc) = state.appsrc.clone() else {
+            return;
+        };
+
+        let caps_key = (image.size, state.framerate);
+        if state.caps != Some(caps_key) {
+            match capture_caps(image.size, state.framerate) {
+                Ok(caps) => appsrc.set_caps(Some(&caps)),
+                Err(e) => {
+                    eprintln!("Caps non validi per il frame: {:?}", e);
+                    return;
+                }
+            }
+            state.caps = Some(caps_key);
+        }
+
+        let buffer = gst::Buffer::from_mut_slice(image.as_raw().to_vec());
+        match appsrc.push_buffer(buffer) {
+            Ok(_) => state.fps.tick(),
+            // la pipeline si sta fermando o ricostruendo
+            Err(gst::FlowError::Flushing) => {}
+            Err(e) => eprintln!("Errore nell'invio del frame: {:?}", e),
+        }
+    }
+
+    fn fps(&self) -> f32 {
+        self.0.lock().unwrap().fps.fps()
+    }
+}
+
+pub struct StreamingServer {
+    pipeline: gst::Pipeline,
+    settings: StreamSettings,
+    ports: StreamPorts,
+    input: FrameInput,
+    encoder: &'static str,
+    control: ControlServer,
+    viewers: Vec<Viewer>,
+    signalling: Option<SignallingServer>,
+    web_peers: Vec<WebPeer>,
+    tcp_peers: Vec<TcpPeer>,
+    hls_server: Option<FileServer>,
+    // controllo remoto: richieste in attesa di approvazione e receiver che ha il controllo
+    control_requests: Vec<usize>,
+    controller: Option<usize>,
+    injector: Option<InputInjector>,
+    input_area: Rect,
+    // ping e tratti dei viewer, mostrati sull'anteprima
+    marks: MarkBoard,
+    // ultima posizione del cursore inviata in modalità metadati
+    cursor: Option<Pos2>,
+    last_keyframe: Option<Instant>,
+    keyframe_pending: bool,
+}
+
+impl StreamingServer {
+    pub fn start(settings: StreamSettings, ports: StreamPorts) -> Result<StreamingServer, Box<dyn Error>> {
+        // Inizializza GStreamer
+        gst::init()?;
+        let control = ControlServer::start(ports.control())?;
+        let signalling = if settings.webrtc { Some(SignallingServer::start(ports.signalling())?) } else { None };
+        let hls_server = if settings.hls.is_some() { Some(start_hls_server(ports)?) } else { None };
+        let (pipeline, encoder) = build_pipeline(&settings, ports)?;
+        let input = FrameInput::new(&pipeline, settings.framerate);
+        println!("Streaming {} ({}) in esecuzione, controllo sulla porta {}...", settings.codec.name(), encoder, ports.control());
+
+        Ok(StreamingServer {
+            pipeline,
+            settings,
+            ports,
+            input,
+            encoder,
+            control,
+            viewers: Vec::new(),
+            signalling,
+            web_peers: Vec::new(),
+            tcp_peers: Vec::new(),
+            hls_server,
+            control_requests: Vec::new(),
+            controller: None,
+            injector: None,
+            input_area: Rect::NOTHING,
+            marks: MarkBoard::default(),
+            cursor: None,
+            last_keyframe: None,
+            keyframe_pending: false,
+        })
+    }
+
+    pub fn settings(&self) -> &StreamSettings {
+        &self.settings
+    }
+
+    pub fn encoder(&self) -> &'static str {
+        self.encoder
+    }
+
+    /// Names of the connected receivers with the transport they use.
+    pub fn viewers(&self) -> Vec<String> {
+        self.viewers.iter()
+            .map(|v| format!("{} ({})", v.name, v.transport.name()))
+            .chain(self.web_peers.iter().map(|p| format!("{} (WebRTC)", p.ip)))
+            .collect()
+    }
+
+    /// Rebuilds the pipeline with new settings while keeping the receivers connected;
+    /// on a codec change the receivers are told to follow.
+    pub fn apply(&mut self, settings: StreamSettings) -> Result<(), Box<dyn Error>> {
+        if settings == self.settings {
+            return Ok(());
+        }
+        if settings.cursor.mode != CursorMode::Metadata && self.cursor.take().is_some() {
+            self.control.broadcast(&ControlMessage::Cursor(None));
+        }
+        // framerate e FEC si cambiano a pipeline avviata: caps dei prossimi frame e proprietà dell'encoder FEC.
+        // Se col framerate cambia anche il GOP si ricostruisce, molti encoder lo leggono solo all'avvio
+        if settings.codec == self.settings.codec
+            && settings.preset == self.settings.preset
+            && settings.keyframe_interval() == self.settings.keyframe_interval()
+            && settings.srt == self.settings.srt
+            && settings.webrtc == self.settings.webrtc
+            && settings.hls == self.settings.hls
+            && settings.audio == self.settings.audio
+        {
+            if settings.fec_percentage != self.settings.fec_percentage {
+                match self.pipeline.by_name("fec") {
+                    Some(fec) => fec.set_property("percentage", settings.fec_percentage),
+                    None => return Err("FEC non disponibile (manca rtpulpfecenc)".into()),
+                }
+            }
+            self.input.set_framerate(settings.framerate);
+            self.settings = settings;
+            return Ok(());
+        }
+        if settings.webrtc && self.signalling.is_none() {
+            self.signalling = Some(SignallingServer::start(self.ports.signalling())?);
+        }
+        if settings.hls.is_some() && self.hls_server.is_none() {
+            self.hls_server = Some(start_hls_server(self.ports)?);
+        }
+        let _ = self.pipeline.set_state(gst::State::Null);
+        let (pipeline, encoder) = match build_pipeline(&settings, self.ports) {
+            Ok(p) => p,
+            Err(e) => {
+                // torna alle impostazioni precedenti
+                self.pipeline.set_state(gst::State::Playing)?;
+                return Err(e);
+            }
+        };
+        self.pipeline = pipeline;
+        self.input.reset(&self.pipeline, settings.framerate);
+        self.encoder = encoder;
+        self.refresh_udp_clients();
+        if settings.codec != self.settings.codec {
+            self.control.broadcast(&ControlMessage::Codec(settings.codec));
+        }
+        if settings.audio != self.settings.audio {
+            self.control.broadcast(&ControlMessage::Audio(self.has_audio()));
+        }
+        self.settings = settings;
+
+        // anche i sink TCP sono spariti: porte nuove a chi usa il TCP, che ricostruisce la pipeline con quelle
+        let tcp: Vec<usize> = self.tcp_peers.drain(..).map(|p| p.id).collect();
+        for id in tcp {
+            self.add_tcp_peer(id);
+        }
+
+        // i bin dei browser sono spariti con la vecchia pipeline: si rinegozia da capo con una nuova offerta
+        let peers: Vec<(usize, IpAddr)> = self.web_peers.drain(..).map(|p| (p.id, p.ip)).collect();
+        if !self.settings.webrtc {
+            self.signalling = None;
+        }
+        if self.settings.hls.is_none() {
+            self.hls_server = None;
+        }
+        if let Some(signalling) = &self.signalling {
+            for (id, ip) in peers {
+                match WebPeer::add(&self.pipeline, self.settings.codec, id, ip, signalling.sender()) {
+                    Ok(peer) => self.web_peers.push(peer),
+                    Err(e) => eprintln!("Impossibile ricollegare il browser {}: {:?}", id, e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_tcp_peer(&mut self, id: usize) {
+        match TcpPeer::add(&self.pipeline, id) {
+            Ok((peer, ports)) => {
+                self.tcp_peers.push(peer);
+                self.control.send(id, &ControlMessage::TcpPorts(ports));
+            }
+            Err(e) => eprintln!("Impossibile servire il receiver {} in TCP: {:?}", id, e),
+        }
+    }
+
+    fn remove_tcp_peer(&mut self, id: usize) {
+        if let Some(index) = self.tcp_peers.iter().position(|p| p.id == id) {
+            self.tcp_peers.remove(index).remove(&self.pipeline);
+        }
+    }
+
+    fn viewer_name(&self, id: usize) -> String {
+        match self.viewers.iter().find(|v| v.id == id) {
+            Some(viewer) if !viewer.name.is_empty() => viewer.name.clone(),
+            Some(viewer) => viewer.ip.to_string(),
+            None => format!("receiver {}", id),
+        }
+    }
+
+    /// Oldest remote-control request still waiting for the user's answer.
+    pub fn pending_control_request(&self) -> Option<(usize, String)> {
+        self.control_requests.first().map(|id| (*id, self.viewer_name(*id)))
+    }
+
+    /// Name of the receiver currently controlling this machine.
+    pub fn controller(&self) -> Option<String> {
+        self.controller.map(|id| self.viewer_name(id))
+    }
+
+    /// Grants or denies a request; granting takes the control away from the previous receiver.
+    pub fn answer_control_request(&mut self, id: usize, allow: bool) {
+        self.control_requests.retain(|r| *r != id);
+        if !allow {
+            self.control.send(id, &ControlMessage::ControlGrant(false));
+            return;
+        }
+        if self.injector.is_none() {
+            match InputInjector::new() {
+                Ok(injector) => self.injector = Some(injector),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    self.control.send(id, &ControlMessage::ControlGrant(false));
+                    return;
+                }
+            }
+        }
+        self.revoke_control();
+        self.controller = Some(id);
+        self.control.send(id, &ControlMessage::ControlGrant(true));
+    }
+
+    /// Takes the control back immediately, the receiver is told to stop sending input.
+    pub fn revoke_control(&mut self) {
+        if let Some(id) = self.controller.take() {
+            self.control.send(id, &ControlMessage::ControlGrant(false));
+        }
+    }
+
+    /// Screen rectangle shown in the stream, used to place the remote pointer.
+    pub fn set_input_area(&mut self, area: Rect) {
+        self.input_area = area;
+    }
+
+    fn inject_input(&mut self, id: usize, event: InputEvent) {
+        if self.controller != Some(id) {
+            return;
+        }
+        if let Some(injector) = self.injector.as_mut() {
+            if let Err(e) = injector.inject(&event, self.input_area) {
+                eprintln!("Input remoto non iniettato: {}", e);
+            }
+        }
+    }
+
+    /// Pings and strokes of the viewers still on screen.
+    pub fn viewer_marks(&mut self) -> &MarkBoard {
+        self.marks.expire();
+        &self.marks
+    }
+
+    /// Forwards the cursor to the receivers when it is sent as metadata; positions only when they change.
+    pub fn send_cursor(&mut self, sample: &CursorSample) {
+        if self.settings.cursor.mode != CursorMode::Metadata {
+            return;
+        }
+        if sample.pos != self.cursor {
+            self.cursor = sample.pos;
+            self.control.broadcast(&ControlMessage::Cursor(sample.pos));
+        }
+        if self.settings.cursor.ripple {
+            for click in &sample.clicks {
+                self.control.broadcast(&ControlMessage::Click(*click));
+            }
+        }
+    }
+
+    // l'audio può mancare anche se richiesto, quando non ci sono sorgenti o encoder Opus
+    fn has_audio(&self) -> bool {
+        self.pipeline.by_name("audiosink").is_some()
+    }
+
+    /// Mute toggles for the system audio and the microphone, applied to the running pipeline.
+    pub fn set_audio_muted(&self, system: bool, microphone: bool) {
+        audio::set_muted(&self.pipeline, system, microphone);
+    }
+
+    /// Sends a captured frame to the encoder.
+    /// Input of the current pipeline, for the capture thread.
+    pub fn frame_input(&self) -> FrameInput {
+        self.input.clone()
+    }
+
+    /// Frames per second actually pushed into the encoder.
+    pub fn sent_fps(&self) -> f32 {
+        self.input.fps()
+    }
+
+    /// Schedules an IDR frame; requests closer than KEYFRAME_MIN_INTERVAL are merged into one.
+    pub fn request_keyframe(&mut self) {
+        self.keyframe_pending = true;
+    }
 
-    // Avvia la pipeline
-    pipeline.set_state(gst::State::Playing)?;
+    // Asks the encoder for an IDR frame with all the headers, so receivers can resync
+    // anche l'encoder dedicato ai browser, se c'è, perché chi si collega aspetta un keyframe
+    fn force_keyframe(&self) {
+        for name in ["encoder", "webrtc_encoder"] {
+            let Some(pad) = self.pipeline.by_name(name).and_then(|e| e.static_pad("src")) else {
+                continue;
+            };
+            let event = UpstreamForceKeyUnitEvent::builder().all_headers(true).build();
+            if !pad.send_event(event) {
+                eprintln!("L'encoder {} non ha accettato la richiesta di keyframe", self.encoder);
+            }
+        }
+    }
 
-    println!("Server UDP in esecuzione su 127.0.0.1:5000...");
+    // i receiver su TCP si collegano da soli a tcpserversink, quelli su UDP vanno aggiunti a multiudpsink;
+    // l'RTCP con cui i receiver allineano audio e video va a tutti in UDP
+    fn refresh_udp_clients(&self) {
+        let mut clients: Vec<(&str, IpAddr, i32)> = Vec::new();
+        for viewer in &self.viewers {
+            let Some(udp) = viewer.udp else { continue };
+            if viewer.transport == Transport::Udp {
+                clients.push(("udpsink", viewer.ip, udp.video));
+                clients.push(("audiosink", viewer.ip, udp.audio));
+            }
+            clients.push(("rtcpsink", viewer.ip, udp.rtcp));
+            clients.push(("audio_rtcpsink", viewer.ip, udp.audio_rtcp));
+        }
+        // ogni receiver ascolta sulle sue porte, anche quando più receiver stanno sullo stesso indirizzo
+        clients.sort();
+        clients.dedup();
+        for name in ["udpsink", "audiosink", "rtcpsink", "audio_rtcpsink"] {
+            let Some(sink) = self.pipeline.by_name(name) else { continue };
+            sink.emit_by_name::<()>("clear", &[]);
+            for (_, ip, port) in clients.iter().filter(|(n, _, port)| *n == name && *port > 0) {
+                sink.emit_by_name::<()>("add", &[&ip.to_string(), port]);
+            }
+        }
+    }
 
-    // Mantieni il server attivo
-    let bus = pipeline.bus().unwrap();
-    for msg in bus.iter_timed(gst::ClockTime::NONE) {
-        match msg.view() {
-            gst::MessageView::Error(err) => {
-                eprintln!("Errore: {:?}", err);
-                break;
+    // negoziazione dei browser: un webrtcbin per ognuno, creato alla connessione del WebSocket
+    fn poll_signalling(&mut self) {
+        let Some(signalling) = &self.signalling else {
+            return;
+        };
+        while let Some(event) = signalling.try_recv() {
+            match event {
+                SignallingEvent::Connected(id, ip) => {
+                    println!("Browser {} connesso da {}", id, ip);
+                    match WebPeer::add(&self.pipeline, self.settings.codec, id, ip, signalling.sender()) {
+                        Ok(peer) => self.web_peers.push(peer),
+                        Err(e) => eprintln!("Impossibile servire il browser {}: {:?}", id, e),
+                    }
+                }
+                SignallingEvent::Disconnected(id) => {
+                    if let Some(index) = self.web_peers.iter().position(|p| p.id == id) {
+                        self.web_peers.remove(index).remove(&self.pipeline);
+                    }
+                }
+                SignallingEvent::Message(id, SignallingMessage::Answer(sdp)) => {
+                    let Some(webrtcbin) = self.web_peers.iter().find(|p| p.id == id).and_then(|p| p.webrtcbin()) else {
+                        continue;
+                    };
+                    match gst_sdp::SDPMessage::parse_buffer(sdp.as_bytes()) {
+                        Ok(sdp) => {
+                            let answer = gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Answer, sdp);
+                            webrtcbin.emit_by_name::<()>("set-remote-description", &[&answer, &None::<gst::Promise>]);
+                            // il browser può mostrare il video solo dal primo keyframe
+                            self.keyframe_pending = true;
+                        }
+                        Err(e) => eprintln!("Risposta SDP non valida dal browser {}: {}", id, e),
+                    }
+                }
+                SignallingEvent::Message(id, SignallingMessage::Ice(mline, candidate)) => {
+                    if let Some(webrtcbin) = self.web_peers.iter().find(|p| p.id == id).and_then(|p| p.webrtcbin()) {
+                        webrtcbin.emit_by_name::<()>("add-ice-candidate", &[&mline, &candidate]);
+                    }
+                }
+                SignallingEvent::Message(_, SignallingMessage::Offer(_)) => {}
             }
-            gst::MessageView::Eos(..) => {
-                println!("Fine dello streaming");
-                break;
+        }
+    }
+
+    /// Handles control channel events and pipeline messages, called once per UI frame.
+    pub fn poll(&mut self) {
+        while let Some(event) = self.control.try_recv() {
+            match event {
+                ControlEvent::Connected(id, ip) => {
+                    println!("Receiver {} connesso da {}", id, ip);
+                    // si parte con l'UDP, il receiver comunica se passa al TCP
+                    self.viewers.push(Viewer { id, ip, name: String::new(), transport: Transport::Udp, udp: None });
+                    self.refresh_udp_clients();
+                    // l'audio prima del codec: il receiver costruisce la pipeline appena riceve il codec
+                    self.control.send(id, &ControlMessage::Audio(self.has_audio()));
+                    self.control.send(id, &ControlMessage::Codec(self.settings.codec));
+                    if self.cursor.is_some() {
+                        self.control.send(id, &ControlMessage::Cursor(self.cursor));
+                    }
+                    // chi arriva a stream avviato non deve aspettare il prossimo keyframe periodico
+                    self.request_keyframe();
+                }
+                ControlEvent::Disconnected(id) => {
+                    self.viewers.retain(|v| v.id != id);
+                    self.control_requests.retain(|r| *r != id);
+                    if self.controller == Some(id) {
+                        self.controller = None;
+                    }
+                    self.remove_tcp_peer(id);
+                    self.refresh_udp_clients();
+                }
+                ControlEvent::Message(id, ControlMessage::Hello(name)) => {
+                    println!("Receiver {}: {}", id, name);
+                    if let Some(viewer) = self.viewers.iter_mut().find(|v| v.id == id) {
+                        viewer.name = name;
+                    }
+                }
+                ControlEvent::Message(id, ControlMessage::Transport(transport)) => {
+                    if let Some(viewer) = self.viewers.iter_mut().find(|v| v.id == id) {
+                        println!("Receiver {} passa a {}", id, transport.name());
+                        viewer.transport = transport;
+                    }
+                    // porte nuove a ogni richiesta: quelle vecchie hanno già servito il loro client
+                    self.remove_tcp_peer(id);
+                    if transport == Transport::Tcp && self.viewers.iter().any(|v| v.id == id) {
+                        self.add_tcp_peer(id);
+                    }
+                    self.refresh_udp_clients();
+                    self.request_keyframe();
+                }
+                ControlEvent::Message(id, ControlMessage::UdpPorts(udp)) => {
+                    if let Some(viewer) = self.viewers.iter_mut().find(|v| v.id == id) {
+                        viewer.udp = Some(udp);
+                    }
+                    self.refresh_udp_clients();
+                }
+                ControlEvent::Message(_, ControlMessage::KeyframeRequest) => {
+                    self.request_keyframe();
+                }
+                // il controllo si concede solo dall'interfaccia del caster
+                ControlEvent::Message(id, ControlMessage::ControlRequest) => {
+                    if self.controller != Some(id) && !self.control_requests.contains(&id) {
+                        self.control_requests.push(id);
+                    }
+                }
+                ControlEvent::Message(id, ControlMessage::ControlRelease) => {
+                    self.control_requests.retain(|r| *r != id);
+                    if self.controller == Some(id) {
+                        self.controller = None;
+                    }
+                }
+                ControlEvent::Message(id, ControlMessage::Input(event)) => {
+                    self.inject_input(id, event);
+                }
+                ControlEvent::Message(id, ControlMessage::Mark(mark)) => {
+                    let name = self.viewer_name(id);
+                    self.marks.push(name, mark);
+                }
+                ControlEvent::Message(_, _) => {}
+            }
+        }
+
+        self.poll_signalling();
+
+        if self.keyframe_pending && self.last_keyframe.map_or(true, |t| t.elapsed() >= KEYFRAME_MIN_INTERVAL) {
+            self.force_keyframe();
+            self.keyframe_pending = false;
+            self.last_keyframe = Some(Instant::now());
+        }
+
+        if let Some(bus) = self.pipeline.bus() {
+            while let Some(msg) = bus.pop() {
+                match msg.view() {
+                    gst::MessageView::Error(err) => {
+                        eprintln!("Errore: {:?}", err);
+                    }
+                    gst::MessageView::Eos(..) => {
+                        println!("Fine dello streaming");
+                    }
+                    _ => {}
+                }
             }
-            _ => {}
         }
     }
+}
 
-    // Ferma la pipeline
-    pipeline.set_state(gst::State::Null)?;
-    Ok(())
+impl Drop for StreamingServer {
+    fn drop(&mut self) {
+        // Ferma la pipeline
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
 }
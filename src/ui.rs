@@ -1,20 +1,36 @@
 pub mod ui {
 
-    use crate::receiver::start_video_receiver;
-use egui::TextureHandle;
+    use crate::receiver::{LatencyMode, ReceiverSession, RemoteControl};
+    use crate::remote::{is_special_key, InputEvent};
+    use crate::annotation::{AnnotationLayer, MarkBoard, MarkPad, Tool};
+    use crate::cursor::{paint_remote_cursor, CursorMode};
+    use crate::zoom::{VideoLayout, ZoomView, ZOOM_STEP};
+    use crate::audio::{microphone_available, output_devices, system_audio_available, MAX_LIP_SYNC_MS, MAX_VOLUME_PERCENT, METER_FLOOR_DB};
     use eframe::epaint::textures::TextureOptions;
-    use egui::{Button, Color32, ColorImage, Context, Image, ImageButton, Key, Pos2, Rect, Rounding, Stroke};
+    use egui::{Button, Color32, ColorImage, Context, Image, ImageButton, Key, Pos2, Rect, Rounding, Stroke, TextureHandle};
     use egui::load::SizedTexture;
     use gstreamer::Element;
-    use crate::{MouseDragHandler, MyApp, State};
-    use crate::capture::capture::{get_monitors};
+    use crate::{MouseDragHandler, MyApp, PipWindow, ReceiverTile, State, MIN_WINDOW_SIZE};
+    use crate::capture::capture::{get_monitors, get_windows, CaptureRate, CaptureSource, FrameQueue};
+    use std::sync::Arc;
     use crate::State::{MainMenu, Sending};
 
     const TOP_PANEL_HEIGHT: f32 = 40.0;
     const SIDE_PANEL_WIDTH: f32 = 85.0;
+    const VIDEO_TEXTURE: &str = "video_frame_texture";
+    // spazio tra i riquadri della griglia e dimensione dei loro pulsanti
+    const GRID_GAP: f32 = 3.0;
+    const TILE_BUTTON_SIZE: egui::Vec2 = egui::vec2(56.0, 20.0);
+    const PIP_SIZE: egui::Vec2 = egui::vec2(384.0, 216.0);
+    const PIP_MIN_SIZE: egui::Vec2 = egui::vec2(160.0, 90.0);
+    // lato dell'angolo che ridimensiona la finestra PiP
+    const PIP_GRIP: f32 = 16.0;
+    // conversione degli scroll in righe per il controllo remoto
+    const SCROLL_POINTS_PER_LINE: f32 = 40.0;
+    const SCROLL_LINES_PER_PAGE: f32 = 20.0;
     use gstreamer_app::{gst, AppSink, AppSinkCallbacks};
     use gstreamer::prelude::*;
-    use crate::streaming::start_server;
+    use crate::streaming::{Codec, HlsSettings, QualityPreset, SrtMode, StreamSettings, StreamingServer, Transport, MAX_FEC_PERCENTAGE, MAX_STREAMS};
 
     pub fn main_menu_ui(ctx: &Context, app: &mut MyApp) {
         egui::TopBottomPanel::top("title")
@@ -52,14 +68,16 @@ use egui::TextureHandle;
 
     pub fn sender_ui(ctx: &Context, app: &mut MyApp) {
 
-        //handle hotkeys
-        ctx.input(|i| {
-            for (_, v, s) in app.keys.iter_mut() {
-                if i.key_pressed(*v) {
-                    *s = !*s;
+        //handle hotkeys, except while typing in a text field (annotation text, SRT passphrase...)
+        if !ctx.wants_keyboard_input() {
+            ctx.input(|i| {
+                for (_, v, s) in app.keys.iter_mut() {
+                    if i.key_pressed(*v) {
+                        *s = !*s;
+                    }
                 }
-            }
-        });
+            });
+        }
 
         egui::TopBottomPanel::top("title")
             .exact_height(TOP_PANEL_HEIGHT)
@@ -80,8 +98,8 @@ use egui::TextureHandle;
                     app.state = State::PortionSelection;
                 }
                 ui.add_space(8.0);
-                if ui.add_enabled(app.crop.is_some(), Button::new("FULL SIZE")).clicked() {
-                    app.crop = None;
+                if ui.add_enabled(app.stream().crop.is_some(), Button::new("FULL SIZE")).clicked() {
+                    app.stream_mut().crop = None;
                 }
                 ui.add_space(8.0);
                 ui.visuals_mut().widgets.active.weak_bg_fill = Color32::RED;
@@ -92,96 +110,1056 @@ use egui::TextureHandle;
                 if ui.add(Button::new("MAIN MENU")).clicked() {
                     app.state = MainMenu;
                 }
+
+                ui.add_space(16.0);
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    if annotating(app) {
+                        annotation_tools_ui(ui, app);
+                        ui.add_space(16.0);
+                    }
+                    stream_settings_ui(ui, app);
+                });
             });
 
-        //start_streaming(ctx,app);
-        start_server();
-        video_ui(ctx, app);
+        streams_ui(ctx, app);
+
+        //the revoke hotkey works as a command, not as a toggle, and is read from the whole desktop
+        //because the caster's window rarely has focus while a receiver is in control
+        if let Some((_, key, revoke)) = app.keys.iter_mut().find(|(k, _, _)| k == "REVOKE CONTROL") {
+            if app.revoke_key.pressed(*key) {
+                *revoke = true;
+            }
+            if *revoke {
+                *revoke = false;
+                for server in app.streams.iter_mut().filter_map(|s| s.server.as_mut()) {
+                    server.revoke_control();
+                }
+            }
+        }
+
+        //start the streams once and keep them running while on this page
+        let mut failed = Vec::new();
+        for (index, stream) in app.streams.iter_mut().enumerate() {
+            if stream.server.is_none() {
+                match StreamingServer::start(stream.settings.clone(), stream.ports) {
+                    Ok(server) => stream.server = Some(server),
+                    Err(e) => {
+                        eprintln!("Errore nell'avvio dello stream {}: {:?}", stream.ports.index(), e);
+                        failed.push(index);
+                    }
+                }
+            }
+        }
+        //a stream that cannot start (e.g. its ports are taken) is dropped, the others keep running
+        for index in failed.into_iter().rev() {
+            if app.streams.len() > 1 {
+                app.remove_stream(index);
+            } else {
+                app.state = MainMenu;
+            }
+        }
+        let several = app.streams.len() > 1;
+        for stream in app.streams.iter_mut() {
+            if let Some(server) = stream.server.as_mut() {
+                server.poll();
+                let title = if several { format!("Remote control (stream {})", stream.ports.index()) } else { "Remote control".to_string() };
+                control_request_ui(ctx, server, &title);
+            }
+        }
+        if let Some(rect) = video_ui(ctx, app) {
+            annotation_overlay_ui(ctx, app, rect);
+            if let Some(server) = app.stream_mut().server.as_mut() {
+                let marks = server.viewer_marks();
+                if !marks.is_empty() {
+                    marks.paint(&marks_painter(ctx, rect), rect);
+                    ctx.request_repaint();
+                }
+            }
+        }
     }
 
+    //one entry per published stream: selecting it moves the preview and the side panel onto it
+    fn streams_ui(ctx: &Context, app: &mut MyApp) {
+        egui::TopBottomPanel::bottom("streams")
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.add_space(4.0);
+                let mut select = None;
+                let mut remove = None;
+                ui.horizontal(|ui| {
+                    for (index, stream) in app.streams.iter().enumerate() {
+                        ui.group(|ui| {
+                            ui.vertical(|ui| {
+                                let title = format!("STREAM {} (port {})", stream.ports.index(), stream.ports.control());
+                                if ui.selectable_label(index == app.selected_stream, title).clicked() {
+                                    select = Some(index);
+                                }
+                                let area = if stream.crop.is_some() { "portion" } else { "full" };
+                                ui.small(format!("{}, {}, {}", stream.source_name, area, stream.settings.codec.name()));
+                                let viewers = stream.server.as_ref().map_or(0, |s| s.viewers().len());
+                                ui.small(format!("{} viewers, {:.1} fps", viewers, stream.server.as_ref().map_or(0.0, |s| s.sent_fps())));
+                                if app.streams.len() > 1 && ui.small_button("REMOVE").clicked() {
+                                    remove = Some(index);
+                                }
+                            });
+                        });
+                    }
+                    ui.vertical(|ui| {
+                        if ui.add_enabled(app.streams.len() < MAX_STREAMS, Button::new("ADD STREAM")).clicked() {
+                            app.add_stream();
+                        }
+                        //the crop belongs to the old source, it is dropped with the change
+                        let current = app.stream().source;
+                        let mut source = None;
+                        egui::ComboBox::from_id_salt("stream_source")
+                            .selected_text(app.stream().source_name.clone())
+                            .show_ui(ui, |ui| {
+                                for m in get_monitors() {
+                                    let id = CaptureSource::Monitor(m.id());
+                                    if ui.selectable_label(id == current, m.name().to_string()).clicked() {
+                                        source = Some((id, m.name().to_string()));
+                                    }
+                                }
+                                ui.separator();
+                                for w in get_windows() {
+                                    let id = CaptureSource::Window(w.id());
+                                    if ui.selectable_label(id == current, w.title().to_string()).clicked() {
+                                        source = Some((id, w.title().to_string()));
+                                    }
+                                }
+                            });
+                        if let Some((id, name)) = source.filter(|(id, _)| *id != current) {
+                            let stream = app.stream_mut();
+                            stream.source = id;
+                            stream.source_name = name;
+                            stream.crop = None;
+                        }
+                    });
+                });
+                if let Some(index) = remove {
+                    app.remove_stream(index);
+                } else if let Some(index) = select {
+                    app.select_stream(index);
+                }
+            });
+    }
 
-    pub fn connection_ui(ctx: &Context, app: &mut MyApp){
+    fn marks_painter(ctx: &Context, rect: Rect) -> egui::Painter {
+        ctx.layer_painter(egui::LayerId::new(egui::Order::Foreground, egui::Id::new("viewer_marks")))
+            .with_clip_rect(rect)
+    }
 
+    fn annotating(app: &MyApp) -> bool {
+        app.keys.iter().any(|(k, _, on)| k == "ANNOTATE" && *on)
+    }
 
+    fn annotation_tools_ui(ui: &mut egui::Ui, app: &mut MyApp) {
+        let layer = &mut app.annotations;
+        ui.label("ANNOTATE");
+        egui::ComboBox::from_id_salt("annotation_tool")
+            .width(SIDE_PANEL_WIDTH - 16.0)
+            .selected_text(layer.tool.label())
+            .show_ui(ui, |ui| {
+                for tool in Tool::ALL {
+                    ui.selectable_value(&mut layer.tool, tool, tool.label());
+                }
+            });
+        if layer.tool != Tool::Highlighter {
+            ui.color_edit_button_srgba(&mut layer.color);
+        }
+        if layer.tool == Tool::Text {
+            ui.add(egui::TextEdit::singleline(&mut layer.text)
+                .hint_text("text")
+                .desired_width(SIDE_PANEL_WIDTH - 16.0));
+        }
+        if ui.add(Button::new("UNDO")).clicked() {
+            layer.undo();
+        }
+        if ui.add(Button::new("CLEAR")).clicked() {
+            layer.clear();
+        }
+        ui.small("clear after");
+        ui.add(egui::DragValue::new(&mut layer.timeout_secs)
+            .range(0..=AnnotationLayer::MAX_TIMEOUT_SECS)
+            .suffix(" s"));
+    }
 
-        egui::TopBottomPanel::top("title")
-            .exact_height(TOP_PANEL_HEIGHT)
-            .resizable(false)
-            .show(ctx, |ui| {
-                ui.add_space(8.0);
-                ui.heading("STREAMING");
+    // disegno con il mouse sopra l'anteprima, le forme sono normalizzate sul frame
+    fn annotation_overlay_ui(ctx: &Context, app: &mut MyApp, rect: Rect) {
+        if annotating(app) {
+            let (pos, pressed, down, released) = ctx.input(|i| {
+                (i.pointer.interact_pos(), i.pointer.primary_pressed(), i.pointer.primary_down(), i.pointer.primary_released())
             });
+            if let Some(pos) = pos {
+                let over_video = rect.contains(pos);
+                if (pressed && over_video) || (!pressed && (down || released)) {
+                    let normalized = Pos2::new((pos.x - rect.min.x) / rect.width(), (pos.y - rect.min.y) / rect.height());
+                    app.annotations.pointer(ctx, normalized, pressed, down, released);
+                }
+            }
+            ctx.set_cursor_icon(egui::CursorIcon::Crosshair);
+        }
+        if !app.annotations.is_empty() {
+            let painter = ctx.layer_painter(egui::LayerId::new(egui::Order::Foreground, egui::Id::new("annotations")))
+                .with_clip_rect(rect);
+            app.annotations.paint(&painter, rect);
+        }
+    }
 
-        egui::SidePanel::left("buttons")
-            .exact_width(SIDE_PANEL_WIDTH)
+    // il controllo remoto parte solo dopo l'approvazione esplicita di chi trasmette
+    fn control_request_ui(ctx: &Context, server: &mut StreamingServer, title: &str) {
+        let Some((id, name)) = server.pending_control_request() else {
+            return;
+        };
+        egui::Window::new(title)
+            .collapsible(false)
             .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
             .show(ctx, |ui| {
+                ui.label(format!("{} wants to control your mouse and keyboard.", name));
+                ui.horizontal(|ui| {
+                    if ui.add(Button::new("ALLOW")).clicked() {
+                        server.answer_control_request(id, true);
+                    }
+                    if ui.add(Button::new("DENY")).clicked() {
+                        server.answer_control_request(id, false);
+                    }
+                });
+            });
+    }
+
+
+    fn stream_settings_ui(ui: &mut egui::Ui, app: &mut MyApp) {
+        ui.label("CODEC");
+        let mut settings = app.stream().settings.clone();
+        egui::ComboBox::from_id_salt("codec")
+            .width(SIDE_PANEL_WIDTH - 16.0)
+            .selected_text(settings.codec.name())
+            .show_ui(ui, |ui| {
+                for c in app.available_codecs.iter() {
+                    ui.selectable_value(&mut settings.codec, *c, c.name());
+                }
+            });
+        ui.add_space(8.0);
+        ui.label("QUALITY");
+        egui::ComboBox::from_id_salt("quality")
+            .width(SIDE_PANEL_WIDTH - 16.0)
+            .selected_text(settings.preset.label())
+            .show_ui(ui, |ui| {
+                for p in QualityPreset::ALL {
+                    ui.selectable_value(&mut settings.preset, p, p.label());
+                }
+            });
+        if settings.preset != app.stream().settings.preset {
+            settings.framerate = CaptureRate::Fps(settings.preset.framerate());
+        }
+
+        ui.add_space(8.0);
+        ui.label("FPS");
+        let mut on_change = settings.framerate == CaptureRate::OnChange;
+        let mut fps = settings.framerate.nominal_fps();
+        ui.add_enabled(!on_change, egui::DragValue::new(&mut fps)
+            .range(CaptureRate::MIN_FPS..=CaptureRate::MAX_FPS));
+        ui.checkbox(&mut on_change, "on change");
+        settings.framerate = if on_change { CaptureRate::OnChange } else { CaptureRate::Fps(fps) };
+        if let Some(worker) = &app.stream().worker {
+            ui.small(format!("{:.1} fps", worker.capture_fps()));
+            ui.small(format!("{} dropped", worker.dropped_frames()));
+        }
+        ui.small(format!("{:.1} sent", app.stream().server.as_ref().map_or(0.0, |s| s.sent_fps())));
+
+        ui.add_space(8.0);
+        ui.label("FEC");
+        ui.add(egui::DragValue::new(&mut settings.fec_percentage)
+            .range(0..=MAX_FEC_PERCENTAGE)
+            .suffix("%"));
+
+        ui.add_space(8.0);
+        srt_settings_ui(ui, app, &mut settings);
+
+        ui.add_space(8.0);
+        ui.add_enabled(app.webrtc_available, egui::Checkbox::new(&mut settings.webrtc, "WebRTC"));
+        if app.stream().settings.webrtc {
+            if let Some(url) = &app.stream().webrtc_url {
+                ui.small(url);
+            }
+        }
+
+        ui.add_space(8.0);
+        hls_settings_ui(ui, app, &mut settings);
+
+        ui.add_space(8.0);
+        ui.label("AUDIO");
+        ui.add_enabled(system_audio_available(), egui::Checkbox::new(&mut settings.audio.system, "system"));
+        ui.add_enabled(microphone_available(), egui::Checkbox::new(&mut settings.audio.microphone, "mic"));
+        for (name, label) in [("MUTE AUDIO", "system muted"), ("MUTE MIC", "mic muted")] {
+            if app.keys.iter().any(|(k, _, muted)| k == name && *muted) {
+                ui.small(label);
+            }
+        }
+
+        ui.add_space(8.0);
+        ui.label("CURSOR");
+        egui::ComboBox::from_id_salt("cursor_mode")
+            .width(SIDE_PANEL_WIDTH - 16.0)
+            .selected_text(settings.cursor.mode.label())
+            .show_ui(ui, |ui| {
+                for mode in CursorMode::ALL {
+                    ui.selectable_value(&mut settings.cursor.mode, mode, mode.label());
+                }
+            });
+        ui.checkbox(&mut settings.cursor.ripple, "clicks");
+        if settings.cursor.mode == CursorMode::Metadata && (settings.webrtc || settings.hls.is_some()) {
+            ui.small("not shown in browsers");
+        }
+
+        let stream = app.stream_mut();
+        if settings != stream.settings {
+            match stream.server.as_mut().map(|s| s.apply(settings.clone())) {
+                Some(Err(e)) => eprintln!("Impossibile applicare le impostazioni: {:?}", e),
+                _ => stream.settings = settings,
+            }
+        }
+        if let Some(server) = &app.stream().server {
+            ui.small(server.encoder());
+            let viewers = server.viewers();
+            ui.small(format!("{} viewers", viewers.len()));
+            for viewer in viewers {
+                ui.small(viewer);
+            }
+        }
+
+        let revoke_key = app.keys.iter().find(|(k, _, _)| k == "REVOKE CONTROL").map(|(_, v, _)| *v);
+        if let Some(server) = app.stream_mut().server.as_mut() {
+            if let Some(controller) = server.controller() {
                 ui.add_space(8.0);
+                ui.small(format!("controlled by {}", controller));
                 ui.visuals_mut().widgets.active.weak_bg_fill = Color32::RED;
-                if ui.add(Button::new("CLOSE")).clicked() {
-                    app.ip_address=String::new();
-                    app.state = State::Receiver;
+                let label = match revoke_key {
+                    Some(key) => format!("REVOKE\n({})", key.name()),
+                    None => "REVOKE".to_string(),
+                };
+                if ui.add(Button::new(label)).clicked() {
+                    server.revoke_control();
                 }
-            });
-        // Avvia la ricezione video se non è già stata avviata
-        if !app.video_receiver_started {
-            app.video_receiver_started = true;
+            }
+        }
+    }
 
-            let sender_clone = app.sender_channel.clone(); // Clona il sender
-            if let Err(e) = start_video_receiver(ctx.clone(), sender_clone) {
-                eprintln!("Errore nell'avvio della ricezione video: {:?}", e);
+    // le modifiche ai campi di testo si applicano solo con il pulsante, non a ogni tasto premuto
+    fn srt_settings_ui(ui: &mut egui::Ui, app: &mut MyApp, settings: &mut StreamSettings) {
+        ui.collapsing("SRT", |ui| {
+            let draft = &mut app.srt_draft;
+            egui::ComboBox::from_id_salt("srt_mode")
+                .width(SIDE_PANEL_WIDTH - 24.0)
+                .selected_text(draft.mode.name())
+                .show_ui(ui, |ui| {
+                    for mode in SrtMode::ALL {
+                        ui.selectable_value(&mut draft.mode, mode, mode.name());
+                    }
+                });
+            if draft.mode == SrtMode::Caller {
+                ui.add(egui::TextEdit::singleline(&mut draft.peer)
+                    .hint_text("peer IP")
+                    .desired_width(SIDE_PANEL_WIDTH - 24.0));
+            }
+            ui.add(egui::DragValue::new(&mut draft.latency_ms).range(20..=8000).suffix(" ms"));
+            ui.add(egui::TextEdit::singleline(&mut draft.passphrase)
+                .password(true)
+                .hint_text("passphrase")
+                .desired_width(SIDE_PANEL_WIDTH - 24.0));
+
+            if ui.add(Button::new(if settings.srt.is_some() { "UPDATE" } else { "ENABLE" })).clicked() {
+                settings.srt = Some(draft.clone());
+            }
+            if ui.add_enabled(settings.srt.is_some(), Button::new("DISABLE")).clicked() {
+                settings.srt = None;
             }
+        });
+    }
+
+    fn hls_settings_ui(ui: &mut egui::Ui, app: &mut MyApp, settings: &mut StreamSettings) {
+        ui.add_enabled_ui(app.hls_available, |ui| {
+            ui.collapsing("HLS", |ui| {
+                let draft = &mut app.hls_draft;
+                ui.small("segment");
+                ui.add(egui::DragValue::new(&mut draft.segment_seconds)
+                    .range(1..=HlsSettings::MAX_SEGMENT_SECONDS)
+                    .suffix(" s"));
+                ui.small("playlist");
+                ui.add(egui::DragValue::new(&mut draft.playlist_length)
+                    .range(2..=HlsSettings::MAX_PLAYLIST_LENGTH));
+                // gli altri codec e il 4:4:4 vanno ricodificati in H.264 4:2:0
+                let reencode = settings.codec != Codec::H264 || settings.preset == QualityPreset::Text;
+                if reencode && !app.hls_reencode_available {
+                    ui.small("needs x264enc");
+                }
+
+                if ui.add(Button::new(if settings.hls.is_some() { "UPDATE" } else { "ENABLE" })).clicked() {
+                    settings.hls = Some(*draft);
+                }
+                if ui.add_enabled(settings.hls.is_some(), Button::new("DISABLE")).clicked() {
+                    settings.hls = None;
+                }
+            });
+        });
+        if app.stream().settings.hls.is_some() {
+            if let Some(url) = &app.stream().hls_url {
+                ui.small(url);
+            }
+        }
+    }
+
+    pub fn connection_ui(ctx: &Context, app: &mut MyApp){
+        if app.pip.is_some() {
+            poll_receivers(ctx, app);
+            pip_ui(ctx, app);
+            return;
+        }
+
+        presentation_keys(ctx, app);
+
+        // in presentazione restano solo il video e la barra che compare muovendo il mouse
+        if app.presentation.active {
+            presentation_toolbar(ctx, app);
+        } else {
+            egui::TopBottomPanel::top("title")
+                .exact_height(TOP_PANEL_HEIGHT)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.add_space(8.0);
+                    ui.heading("STREAMING");
+                });
+
+            egui::SidePanel::left("buttons")
+                .exact_width(SIDE_PANEL_WIDTH)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.add_space(8.0);
+                    ui.visuals_mut().widgets.active.weak_bg_fill = Color32::RED;
+                    if ui.add(Button::new("CLOSE")).clicked() {
+                        app.close_receiver(app.focused);
+                    }
+                    ui.add_space(8.0);
+                    ui.visuals_mut().widgets.active.weak_bg_fill = Color32::LIGHT_GREEN;
+                    if ui.add(Button::new("ADD\nCASTER")).clicked() {
+                        app.ip_address = String::new();
+                        app.state = State::Receiver;
+                    }
+                    if app.receivers.len() > 1 && ui.add(Button::new("GRID").selected(app.grid)).clicked() {
+                        app.grid = !app.grid;
+                    }
+                    ui.add_space(8.0);
+                    if let Some(session) = app.receivers.get_mut(app.focused).map(|t| &mut t.session) {
+                        ui.visuals_mut().widgets.active.weak_bg_fill = Color32::YELLOW;
+                        match session.remote_control() {
+                            RemoteControl::Off => {
+                                if ui.add(Button::new("REQUEST\nCONTROL")).clicked() {
+                                    session.request_control();
+                                }
+                            }
+                            RemoteControl::Requested => {
+                                if ui.add(Button::new("CANCEL\nREQUEST")).clicked() {
+                                    session.release_control();
+                                }
+                            }
+                            RemoteControl::Granted => {
+                                if ui.add(Button::new("RELEASE\nCONTROL")).clicked() {
+                                    session.release_control();
+                                }
+                            }
+                        }
+                    }
+                    if !app.receivers.is_empty() {
+                        ui.add_space(8.0);
+                        if ui.add(Button::new("POINT").selected(app.mark_pad.enabled)).clicked() {
+                            app.mark_pad.enabled = !app.mark_pad.enabled;
+                        }
+                        if app.mark_pad.enabled {
+                            ui.color_edit_button_srgba(&mut app.mark_pad.color);
+                        }
+                    }
+                    if !app.grid_shown() && focused_texture(app).is_some() {
+                        ui.add_space(8.0);
+                        ui.label("ZOOM");
+                        if ui.add(Button::new("FIT")).clicked() {
+                            app.zoom.fit();
+                        }
+                        if ui.add(Button::new("1:1")).clicked() {
+                            app.zoom.actual_pixels(ctx.pixels_per_point());
+                        }
+                        ui.small(format!("{:.0}%", app.zoom.percent()));
+                    }
+                    if !app.receivers.is_empty() {
+                        ui.add_space(8.0);
+                        presentation_settings_ui(ui, ctx, app);
+                    }
+                    ui.add_space(16.0);
+                    receiver_settings_ui(ui, app);
+                    if focused_session(app).is_some_and(|s| s.has_audio()) {
+                        ui.add_space(16.0);
+                        playback_ui(ui, app);
+                    }
+                });
         }
 
+        poll_receivers(ctx, app);
+
         // Visualizza il video
-        rece_ui(ctx,app);
+        let controlled = focused_session(app).is_some_and(|s| s.remote_control() == RemoteControl::Granted);
+        let video_layout = rece_ui(ctx, app, !controlled);
+        app.video_area = video_layout.map(|layout| layout.visible);
+
+        if let Some(session) = app.receivers.get(app.focused).map(|t| &t.session) {
+            if let Some(layout) = video_layout {
+                if controlled {
+                    forward_input(ctx, &layout, session);
+                } else if app.mark_pad.enabled {
+                    mark_input(ctx, &layout, session, &mut app.mark_pad, &mut app.sent_marks);
+                }
+                app.sent_marks.expire();
+                let rect = layout.frame;
+                let painter = marks_painter(ctx, layout.visible);
+                app.sent_marks.paint(&painter, rect);
+                app.mark_pad.paint(&painter, rect);
+                if let Some(pos) = session.cursor() {
+                    paint_remote_cursor(&painter, rect, pos, session.clicks());
+                }
+            }
+            let top = if app.presentation.active { 0.0 } else { TOP_PANEL_HEIGHT };
+            stats_overlay(ctx, session, top);
+        }
+    }
 
+    // Gestisce i messaggi dei caster (es. cambio di codec), le impostazioni valgono per quello selezionato
+    fn poll_receivers(ctx: &Context, app: &mut MyApp) {
+        if let Some(tile) = app.receivers.get_mut(app.focused) {
+            if tile.session.settings() != &app.receiver_settings {
+                tile.session.apply(app.receiver_settings.clone());
+            }
+        }
+        for tile in app.receivers.iter_mut() {
+            tile.session.poll();
+        }
+        if app.receivers.is_empty() {
+            app.state = State::Receiver;
+        }
+        update_receiver_textures(ctx, app);
+    }
 
+    // la finestra principale diventa la PiP: piccola, senza bordi e sempre in primo piano; all'uscita torna com'era
+    pub fn set_pip(ctx: &Context, app: &mut MyApp, active: bool) {
+        if active == app.pip.is_some() {
+            return;
+        }
+        if active {
+            set_presentation(ctx, app, false);
+            let (position, size) = ctx.input(|i| (i.viewport().outer_rect.map(|r| r.min), i.viewport().inner_rect.map(|r| r.size())));
+            app.pip = Some(PipWindow { position, size });
+            ctx.send_viewport_cmd(egui::ViewportCommand::Decorations(false));
+            ctx.send_viewport_cmd(egui::ViewportCommand::WindowLevel(egui::WindowLevel::AlwaysOnTop));
+            ctx.send_viewport_cmd(egui::ViewportCommand::MinInnerSize(PIP_MIN_SIZE));
+            ctx.send_viewport_cmd(egui::ViewportCommand::InnerSize(PIP_SIZE));
+        } else if let Some(window) = app.pip.take() {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Decorations(true));
+            ctx.send_viewport_cmd(egui::ViewportCommand::WindowLevel(egui::WindowLevel::Normal));
+            ctx.send_viewport_cmd(egui::ViewportCommand::MinInnerSize(MIN_WINDOW_SIZE));
+            if let Some(size) = window.size {
+                ctx.send_viewport_cmd(egui::ViewportCommand::InnerSize(size));
+            }
+            if let Some(position) = window.position {
+                ctx.send_viewport_cmd(egui::ViewportCommand::OuterPosition(position));
+            }
+        }
     }
-    pub fn update_video_texture(ctx: &Context, app: &mut MyApp) {
-        if let Some(receiver) = &app.receiver_channel {
-            while let Ok(image) = receiver.try_recv() {
-                let texture = ctx.load_texture(
-                    "video_frame_texture",
-                    image.clone(),
-                    egui::TextureOptions::LINEAR,
-                );
-                app.texture = Some(texture);
+
+    // in PiP la finestra mostra solo il video del caster selezionato
+    fn pip_ui(ctx: &Context, app: &mut MyApp) {
+        let mut back = false;
+        egui::CentralPanel::default().frame(egui::Frame::none().fill(Color32::BLACK)).show(ctx, |ui| {
+            let panel = ui.max_rect();
+            let response = ui.allocate_rect(panel, egui::Sense::click_and_drag());
+            if let Some(texture) = focused_texture(app) {
+                let size = texture.size_vec2();
+                let scale = (panel.width() / size.x).min(panel.height() / size.y);
+                let uv = Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0));
+                ui.painter().image(texture.id(), Rect::from_center_size(panel.center(), size * scale), uv, Color32::WHITE);
+            }
+            // senza bordi la finestra si sposta trascinando il video e si ridimensiona dall'angolo
+            let grip = Rect::from_min_max(panel.max - egui::Vec2::splat(PIP_GRIP), panel.max);
+            if response.drag_started_by(egui::PointerButton::Primary) {
+                let on_grip = response.interact_pointer_pos().is_some_and(|p| grip.contains(p));
+                ctx.send_viewport_cmd(if on_grip {
+                    egui::ViewportCommand::BeginResize(egui::ResizeDirection::SouthEast)
+                } else {
+                    egui::ViewportCommand::StartDrag
+                });
             }
+            if response.double_clicked() {
+                back = true;
+            }
+            if response.hovered() {
+                let stroke = Stroke::new(1.5, Color32::from_white_alpha(180));
+                for offset in [4.0, 8.0, 12.0] {
+                    ui.painter().line_segment([Pos2::new(grip.max.x - offset, grip.max.y - 2.0), Pos2::new(grip.max.x - 2.0, grip.max.y - offset)], stroke);
+                }
+                ui.painter().text(panel.left_top() + egui::vec2(6.0, 6.0), egui::Align2::LEFT_TOP,
+                    "double click to go back", egui::FontId::proportional(11.0), Color32::from_white_alpha(180));
+            }
+        });
+        if back || ctx.input(|i| i.key_pressed(Key::Escape)) {
+            set_pip(ctx, app, false);
         }
     }
 
+    fn set_presentation(ctx: &Context, app: &mut MyApp, active: bool) {
+        if active == app.presentation.active {
+            return;
+        }
+        if active {
+            set_pip(ctx, app, false);
+            // la finestra si sposta sul monitor scelto prima di andare a schermo intero
+            if let Some(position) = app.presentation.target_position() {
+                ctx.send_viewport_cmd(egui::ViewportCommand::OuterPosition(position));
+            }
+            app.presentation.moved();
+        }
+        ctx.send_viewport_cmd(egui::ViewportCommand::Fullscreen(active));
+        app.presentation.active = active;
+    }
 
+    // F11 e doppio click sul video entrano ed escono, Esc esce; durante il controllo remoto vale solo F11
+    fn presentation_keys(ctx: &Context, app: &mut MyApp) {
+        let controlled = focused_session(app).is_some_and(|s| s.remote_control() == RemoteControl::Granted);
+        let (toggle, escape, double_click, moved) = ctx.input(|i| {
+            (
+                i.key_pressed(Key::F11),
+                i.key_pressed(Key::Escape),
+                i.pointer.button_double_clicked(egui::PointerButton::Primary),
+                i.pointer.delta() != egui::Vec2::ZERO,
+            )
+        });
+        let on_video = app.video_area.is_some_and(|area| ctx.input(|i| i.pointer.hover_pos()).is_some_and(|p| area.contains(p)));
+        if toggle || (!controlled && !app.mark_pad.enabled && double_click && on_video) {
+            set_presentation(ctx, app, !app.presentation.active);
+        } else if escape && !controlled && app.presentation.active {
+            set_presentation(ctx, app, false);
+        }
+        if moved {
+            app.presentation.moved();
+        }
+    }
 
+    fn presentation_settings_ui(ui: &mut egui::Ui, ctx: &Context, app: &mut MyApp) {
+        if ui.add(Button::new("FULL\nSCREEN")).clicked() {
+            set_presentation(ctx, app, true);
+        }
+        monitor_combo(ui, app);
+        ui.add_space(8.0);
+        if ui.add(Button::new("PiP").selected(app.pip.is_some())).clicked() {
+            set_pip(ctx, app, app.pip.is_none());
+        }
+    }
 
+    fn monitor_combo(ui: &mut egui::Ui, app: &mut MyApp) {
+        let presentation = &mut app.presentation;
+        let selected = presentation.monitors.iter()
+            .find(|m| Some(m.id) == presentation.monitor)
+            .map_or("this monitor", |m| m.name.as_str())
+            .to_string();
+        egui::ComboBox::from_id_salt("presentation_monitor")
+            .width(SIDE_PANEL_WIDTH - 16.0)
+            .selected_text(selected)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut presentation.monitor, None, "this monitor");
+                for m in presentation.monitors.iter() {
+                    ui.selectable_value(&mut presentation.monitor, Some(m.id), m.name.as_str());
+                }
+            });
+    }
 
-    fn create_texture_from_image(ctx: &egui::Context, image: &ColorImage) -> TextureHandle {
-        // I dati dei pixel sono già in formato `Color32`, quindi possiamo passarli direttamente.
-        let bytes = image.pixels.iter().flat_map(|color| color.to_array()).collect::<Vec<u8>>();
+    fn presentation_toolbar(ctx: &Context, app: &mut MyApp) {
+        let hovered = ctx.input(|i| i.pointer.hover_pos()).is_some_and(|p| app.presentation.toolbar.is_some_and(|r| r.contains(p)));
+        if !app.presentation.toolbar_visible() && !hovered {
+            // il cursore sparisce con la barra
+            ctx.set_cursor_icon(egui::CursorIcon::None);
+            app.presentation.toolbar = None;
+            return;
+        }
+        ctx.request_repaint_after(std::time::Duration::from_millis(200));
+        let response = egui::Area::new(egui::Id::new("presentation_toolbar"))
+            .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 8.0))
+            .order(egui::Order::Foreground)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        if ui.add(Button::new("EXIT FULL SCREEN")).clicked() {
+                            set_presentation(ctx, app, false);
+                        }
+                        if ui.add(Button::new("FIT")).clicked() {
+                            app.zoom.fit();
+                        }
+                        if ui.add(Button::new("1:1")).clicked() {
+                            app.zoom.actual_pixels(ctx.pixels_per_point());
+                        }
+                        ui.small(format!("{:.0}%", app.zoom.percent()));
+                        if ui.add(Button::new("PiP")).clicked() {
+                            set_pip(ctx, app, true);
+                        }
+                        ui.visuals_mut().widgets.active.weak_bg_fill = Color32::RED;
+                        if ui.add(Button::new("CLOSE")).clicked() {
+                            app.close_receiver(app.focused);
+                        }
+                    });
+                });
+            });
+        app.presentation.toolbar = Some(response.response.rect);
+    }
 
-        // Crea la texture direttamente con opzioni di filtro
-        let options = egui::TextureOptions {
-            magnification: egui::TextureFilter::Linear,  // Imposta il filtro di ingrandimento
-            minification: egui::TextureFilter::Linear,   // Imposta il filtro di riduzione
-            ..Default::default() // Usa le opzioni di default per gli altri campi
+    // click e trascinamenti sopra al video diventano ping e tratti per il caster, restano visibili anche qui
+    fn mark_input(ctx: &Context, layout: &VideoLayout, session: &ReceiverSession, pad: &mut MarkPad, sent: &mut MarkBoard) {
+        let (pos, pressed, down, released) = ctx.input(|i| {
+            (i.pointer.interact_pos(), i.pointer.primary_pressed(), i.pointer.primary_down(), i.pointer.primary_released())
+        });
+        let Some(pos) = pos else { return };
+        let on_video = layout.contains(pos) && !(layout.zoomed() && layout.minimap().contains(pos));
+        if pressed && !on_video {
+            return;
+        }
+        if on_video {
+            ctx.set_cursor_icon(egui::CursorIcon::Crosshair);
+        }
+        if let Some(mark) = pad.pointer(layout.to_frame(pos), pressed, down, released) {
+            sent.push(String::new(), mark.clone());
+            session.send_mark(mark);
+        }
+    }
+
+    // gli eventi sopra al video diventano coordinate normalizzate sul frame, il caster le riporta sul suo schermo
+    fn forward_input(ctx: &Context, layout: &VideoLayout, session: &ReceiverSession) {
+        let to_frame = |pos: Pos2| {
+            let pos = layout.to_frame(pos);
+            (pos.x, pos.y)
         };
+        // la minimappa resta al receiver
+        let on_video = |pos: Pos2| layout.contains(pos) && !(layout.zoomed() && layout.minimap().contains(pos));
+        let (events, hovered, modifiers) = ctx.input(|i| {
+            (i.events.clone(), i.pointer.hover_pos().is_some_and(on_video), i.modifiers)
+        });
+        for event in events {
+            let input = match event {
+                egui::Event::PointerMoved(pos) if on_video(pos) => {
+                    let (x, y) = to_frame(pos);
+                    InputEvent::Move(x, y)
+                }
+                // il rilascio va inoltrato anche fuori dal video, altrimenti il tasto resta premuto
+                egui::Event::PointerButton { pos, button, pressed, .. } if on_video(pos) || !pressed => {
+                    let (x, y) = to_frame(pos);
+                    session.send_input(InputEvent::Move(x, y));
+                    InputEvent::Button(button, pressed)
+                }
+                egui::Event::MouseWheel { unit, delta, .. } if hovered => {
+                    let lines = match unit {
+                        egui::MouseWheelUnit::Point => delta / SCROLL_POINTS_PER_LINE,
+                        egui::MouseWheelUnit::Line => delta,
+                        egui::MouseWheelUnit::Page => delta * SCROLL_LINES_PER_PAGE,
+                    };
+                    InputEvent::Scroll(-lines.x, -lines.y)
+                }
+                // i tasti che producono caratteri arrivano come testo, tranne nelle scorciatoie
+                // F11 resta al receiver per la presentazione
+                egui::Event::Key { key, pressed, modifiers, .. }
+                    if key != Key::F11 && (is_special_key(key) || modifiers.ctrl || modifiers.alt || modifiers.mac_cmd) =>
+                {
+                    InputEvent::Key(key, pressed, modifiers)
+                }
+                egui::Event::Text(text) if !modifiers.ctrl && !modifiers.alt => InputEvent::Text(text),
+                egui::Event::Paste(text) => InputEvent::Text(text),
+                // egui trasforma ctrl+c e ctrl+x in eventi propri
+                egui::Event::Copy | egui::Event::Cut => {
+                    let key = if event == egui::Event::Copy { Key::C } else { Key::X };
+                    session.send_input(InputEvent::Key(key, true, egui::Modifiers::CTRL));
+                    InputEvent::Key(key, false, egui::Modifiers::CTRL)
+                }
+                _ => continue,
+            };
+            session.send_input(input);
+        }
+    }
+
+    fn receiver_settings_ui(ui: &mut egui::Ui, app: &mut MyApp) {
+        ui.label("LATENCY");
+        egui::ComboBox::from_id_salt("latency")
+            .width(SIDE_PANEL_WIDTH - 16.0)
+            .selected_text(app.receiver_settings.latency.label())
+            .show_ui(ui, |ui| {
+                for mode in LatencyMode::ALL {
+                    ui.selectable_value(&mut app.receiver_settings.latency, mode, mode.label());
+                }
+            });
 
-        ctx.load_texture(
-            "video_frame_texture",  // Nome della texture
-            egui::ColorImage {
-                size: image.size,
-                pixels: image.pixels.clone(),
-            },
-            options,  // Passa le opzioni di texture
-        )
+        ui.add_space(8.0);
+        ui.label("TRANSPORT");
+        egui::ComboBox::from_id_salt("transport")
+            .width(SIDE_PANEL_WIDTH - 16.0)
+            .selected_text(app.receiver_settings.transport.map_or("Auto", |t| t.name()))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut app.receiver_settings.transport, None, "Auto");
+                for transport in Transport::ALL {
+                    ui.selectable_value(&mut app.receiver_settings.transport, Some(transport), transport.name());
+                }
+            });
+
+        // i parametri SRT si scelgono prima di connettersi, cambiarli ricostruirebbe la pipeline
+        if app.receiver_settings.transport == Some(Transport::Srt) && app.state == State::Receiver {
+            let srt = &mut app.receiver_settings.srt;
+            ui.add_space(8.0);
+            ui.label("SRT");
+            egui::ComboBox::from_id_salt("receiver_srt_mode")
+                .width(SIDE_PANEL_WIDTH - 16.0)
+                .selected_text(srt.mode.name())
+                .show_ui(ui, |ui| {
+                    for mode in SrtMode::ALL {
+                        ui.selectable_value(&mut srt.mode, mode, mode.name());
+                    }
+                });
+            ui.add(egui::DragValue::new(&mut srt.latency_ms).range(20..=8000).suffix(" ms"));
+            ui.add(egui::TextEdit::singleline(&mut srt.passphrase)
+                .password(true)
+                .hint_text("passphrase")
+                .desired_width(SIDE_PANEL_WIDTH - 16.0));
+        }
     }
 
-    pub fn rece_ui(ctx: &egui::Context, app: &mut MyApp) {
-        // Assicurati che app.texture sia aggiornato con l'immagine ricevuta
-        update_video_texture(ctx,app);
+    fn playback_ui(ui: &mut egui::Ui, app: &mut MyApp) {
+        let playback = &mut app.receiver_settings.playback;
+        ui.label("AUDIO");
+        egui::ComboBox::from_id_salt("audio_device")
+            .width(SIDE_PANEL_WIDTH - 16.0)
+            .selected_text(playback.device.as_deref().unwrap_or("Default"))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut playback.device, None, "Default");
+                for device in output_devices() {
+                    ui.selectable_value(&mut playback.device, Some(device.clone()), device);
+                }
+            });
+        ui.spacing_mut().slider_width = SIDE_PANEL_WIDTH - 16.0;
+        ui.add(egui::Slider::new(&mut playback.volume_percent, 0..=MAX_VOLUME_PERCENT).show_value(false));
+        ui.checkbox(&mut playback.muted, "mute");
+        ui.small("lip sync");
+        ui.add(egui::DragValue::new(&mut playback.lip_sync_ms)
+            .range(-MAX_LIP_SYNC_MS..=MAX_LIP_SYNC_MS)
+            .suffix(" ms"));
+
+        if let Some(session) = focused_session(app) {
+            let level = ((session.audio_level_db() - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0);
+            ui.add(egui::ProgressBar::new(level as f32).desired_width(SIDE_PANEL_WIDTH - 16.0));
+        }
+    }
 
-        // Visualizza il video
-        video_ui(ctx, app);
+    fn stats_overlay(ctx: &Context, session: &ReceiverSession, top: f32) {
+        egui::Area::new(egui::Id::new("stats_overlay"))
+            .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-8.0, top + 8.0))
+            .order(egui::Order::Foreground)
+            .interactable(false)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    let latency = session.settings().latency;
+                    ui.small(format!("codec: {} over {}", session.codec().name(), session.transport().name()));
+                    ui.small(format!("latency: {} ({} ms)", latency.label(), latency.latency_ms()));
+                    ui.small(format!("lost packets: {}", session.lost_packets()));
+                    ui.small(format!("audio: {}", if session.has_audio() { "Opus" } else { "none" }));
+                    if let Some((recovered, unrecovered)) = session.fec_stats() {
+                        ui.small(format!("FEC recovered: {} / unrecovered: {}", recovered, unrecovered));
+                    }
+                    ui.small(format!("dropped frames: {}", session.dropped_frames()));
+                });
+            });
+    }
+    pub fn update_receiver_textures(ctx: &Context, app: &mut MyApp) {
+        for tile in app.receivers.iter_mut() {
+            // Tiene solo il frame più recente, quelli vecchi vengono scartati
+            if let Some(image) = tile.frames.drain().pop() {
+                // Riusa la stessa texture invece di allocarne una nuova per ogni frame
+                match tile.texture.as_mut() {
+                    Some(texture) => texture.set(image, TextureOptions::LINEAR),
+                    None => tile.texture = Some(ctx.load_texture(VIDEO_TEXTURE, image, TextureOptions::LINEAR)),
+                }
+            }
+        }
+    }
+
+    fn focused_session(app: &MyApp) -> Option<&ReceiverSession> {
+        app.receivers.get(app.focused).map(|t| &t.session)
+    }
+
+    fn focused_texture(app: &MyApp) -> Option<&TextureHandle> {
+        app.receivers.get(app.focused).and_then(|t| t.texture.as_ref())
+    }
+
+    // Tutti i caster affiancati: click per selezionarne uno, doppio click per vederlo da solo
+    fn grid_ui(ctx: &Context, app: &mut MyApp) {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let panel = ui.available_rect_before_wrap();
+            let count = app.receivers.len();
+            let columns = (count as f32).sqrt().ceil() as usize;
+            let rows = count.div_ceil(columns);
+            let cell = egui::vec2(panel.width() / columns as f32, panel.height() / rows as f32);
+            let (mut focus, mut single, mut close, mut mute) = (None, false, None, None);
+
+            for (i, tile) in app.receivers.iter().enumerate() {
+                let offset = egui::vec2((i % columns) as f32 * cell.x, (i / columns) as f32 * cell.y);
+                let rect = Rect::from_min_size(panel.min + offset, cell).shrink(GRID_GAP);
+                let response = ui.allocate_rect(rect, egui::Sense::click());
+                let painter = ui.painter_at(rect);
+                painter.rect_filled(rect, 4.0, Color32::BLACK);
+                if let Some(texture) = &tile.texture {
+                    let size = texture.size_vec2();
+                    let scale = (rect.width() / size.x).min(rect.height() / size.y);
+                    let uv = Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0));
+                    painter.image(texture.id(), Rect::from_center_size(rect.center(), size * scale), uv, Color32::WHITE);
+                }
+                if i == app.focused {
+                    painter.rect_stroke(rect, 4.0, Stroke::new(2.0, Color32::YELLOW));
+                }
+
+                let session = &tile.session;
+                let source = match session.settings().stream {
+                    0 => session.address().to_string(),
+                    stream => format!("{} #{}", session.address(), stream),
+                };
+                let label = format!("{} · {} · {} lost", source, session.codec().name(), session.lost_packets());
+                let galley = painter.layout_no_wrap(label, egui::FontId::proportional(12.0), Color32::WHITE);
+                let label_pos = rect.left_top() + egui::vec2(6.0, 6.0);
+                painter.rect_filled(Rect::from_min_size(label_pos, galley.size()).expand(3.0), 3.0, Color32::from_black_alpha(160));
+                painter.galley(label_pos, galley, Color32::WHITE);
+
+                let close_rect = Rect::from_min_size(rect.right_bottom() - TILE_BUTTON_SIZE - egui::vec2(6.0, 6.0), TILE_BUTTON_SIZE);
+                if ui.put(close_rect, Button::new("CLOSE").small()).clicked() {
+                    close = Some(i);
+                }
+                if session.has_audio() {
+                    // per il riquadro selezionato il mute passa dalle impostazioni del pannello
+                    let muted = if i == app.focused { app.receiver_settings.playback.muted } else { session.settings().playback.muted };
+                    let mute_rect = close_rect.translate(egui::vec2(-(TILE_BUTTON_SIZE.x + 4.0), 0.0));
+                    if ui.put(mute_rect, Button::new(if muted { "UNMUTE" } else { "MUTE" }).small()).clicked() {
+                        mute = Some(i);
+                    }
+                }
+                if response.double_clicked() {
+                    focus = Some(i);
+                    single = true;
+                } else if response.clicked() {
+                    focus = Some(i);
+                }
+            }
+
+            if let Some(i) = mute {
+                if i == app.focused {
+                    app.receiver_settings.playback.muted = !app.receiver_settings.playback.muted;
+                } else {
+                    let session = &mut app.receivers[i].session;
+                    let mut settings = session.settings().clone();
+                    settings.playback.muted = !settings.playback.muted;
+                    session.apply(settings);
+                }
+            }
+            if let Some(i) = focus {
+                app.focus(i);
+                if single {
+                    app.grid = false;
+                }
+            }
+            if let Some(i) = close {
+                app.close_receiver(i);
+            }
+        });
+    }
+
+    // Video del receiver con zoom e spostamento; `navigate` è falso quando il mouse e la tastiera vanno al caster
+    pub fn rece_ui(ctx: &egui::Context, app: &mut MyApp, navigate: bool) -> Option<VideoLayout> {
+        if app.grid_shown() {
+            grid_ui(ctx, app);
+            return None;
+        }
+
+        let mut layout = None;
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let Some(texture) = app.receivers.get(app.focused).and_then(|t| t.texture.as_ref()) else {
+                ui.centered_and_justified(|ui| ui.label("Nessun video ricevuto..."));
+                return;
+            };
+            let ppp = ctx.pixels_per_point();
+            let texture_size = texture.size_vec2();
+            let panel = ui.available_rect_before_wrap();
+            let response = ui.allocate_rect(panel, egui::Sense::click_and_drag());
+            let current = app.zoom.layout(panel, texture_size, ppp);
+
+            // la minimappa sposta la vista anche durante il controllo remoto
+            let mut on_minimap = false;
+            if current.zoomed() {
+                if let Some(pos) = response.interact_pointer_pos().filter(|p| current.minimap().contains(*p)) {
+                    on_minimap = true;
+                    app.zoom.center_on(((pos - current.minimap().min) / current.minimap().size()).to_pos2());
+                }
+            }
+            if navigate && !on_minimap {
+                let (zoom, scroll, hover) = ctx.input(|i| (i.zoom_delta(), i.smooth_scroll_delta.y, i.pointer.hover_pos()));
+                if let Some(pointer) = hover.filter(|p| current.contains(*p)) {
+                    let factor = zoom * (scroll / 200.0).exp();
+                    if factor != 1.0 {
+                        app.zoom.zoom_at(factor, pointer, &current, texture_size);
+                    }
+                }
+                // col segno attivo il tasto sinistro disegna, il centrale sposta sempre
+                if response.dragged_by(egui::PointerButton::Middle)
+                    || (!app.mark_pad.enabled && response.dragged_by(egui::PointerButton::Primary))
+                {
+                    app.zoom.pan(response.drag_delta(), &current);
+                }
+                if !ctx.wants_keyboard_input() {
+                    zoom_shortcuts(ctx, &mut app.zoom, &current, texture_size);
+                }
+            }
+            let current = app.zoom.layout(panel, texture_size, ppp);
+
+            let painter = ui.painter_at(panel);
+            painter.image(texture.id(), current.visible, current.uv, Color32::WHITE);
+            if current.zoomed() {
+                current.paint_minimap(&painter, texture.id());
+            }
+            layout = Some(current);
+        });
+        layout
+    }
+
+    fn zoom_shortcuts(ctx: &Context, zoom: &mut ZoomView, layout: &VideoLayout, texture_size: egui::Vec2) {
+        let center = layout.panel.center();
+        let step = layout.panel.size() * 0.1;
+        ctx.input(|i| {
+            if i.key_pressed(Key::Plus) || i.key_pressed(Key::Equals) {
+                zoom.zoom_at(ZOOM_STEP, center, layout, texture_size);
+            }
+            if i.key_pressed(Key::Minus) {
+                zoom.zoom_at(1.0 / ZOOM_STEP, center, layout, texture_size);
+            }
+            if i.key_pressed(Key::Num0) {
+                zoom.fit();
+            }
+            if i.key_pressed(Key::Num1) {
+                zoom.actual_pixels(i.pixels_per_point);
+            }
+            for (key, direction) in [
+                (Key::ArrowLeft, egui::vec2(1.0, 0.0)),
+                (Key::ArrowRight, egui::vec2(-1.0, 0.0)),
+                (Key::ArrowUp, egui::vec2(0.0, 1.0)),
+                (Key::ArrowDown, egui::vec2(0.0, -1.0)),
+            ] {
+                if i.key_pressed(key) {
+                    zoom.pan(direction * step, layout);
+                }
+            }
+        });
     }
     /*  pub fn monitor_selection_ui(ctx: &Context, app: &mut MyApp, screenshots: Vec<ColorImage>) {
           egui::TopBottomPanel::top("title")
@@ -316,7 +1294,7 @@ use egui::TextureHandle;
 
                                 //to handle crop too small
                                 if x2 - x1 > 5.0 && y2 - y1 > 5.0 {
-                                    app.crop = Some(Rect::from_min_max(Pos2::new(x1, y1), Pos2::new(x2, y2)));
+                                    app.stream_mut().crop = Some(Rect::from_min_max(Pos2::new(x1, y1), Pos2::new(x2, y2)));
                                 }
                                 app.state = Sending;
                             }
@@ -431,9 +1409,20 @@ use egui::TextureHandle;
                 ui.add_space(8.0);
                 ui.visuals_mut().widgets.active.weak_bg_fill = Color32::YELLOW;
                 ui.add_space(8.0);
+                //leaving the page drops the pending connection, the caster sees it close
                 if ui.add(Button::new("MAIN MENU")).clicked() {
+                    app.connecting = None;
                     app.state = MainMenu;
                 }
+                if !app.receivers.is_empty() {
+                    ui.add_space(8.0);
+                    if ui.add(Button::new("BACK")).clicked() {
+                        app.connecting = None;
+                        app.state = State::Connection;
+                    }
+                }
+                ui.add_space(16.0);
+                receiver_settings_ui(ui, app);
             });
 
         egui::CentralPanel::default().show(ctx, |ui| {
@@ -469,32 +1458,55 @@ use egui::TextureHandle;
 
                 app.ip_address = parts.join(".");
 
-                if ui.add(Button::new("Connect")).clicked() {
+                //the caster can publish several streams, each on its own ports
+                ui.add_space(8.0);
+                ui.label("Stream:");
+                ui.add(egui::DragValue::new(&mut app.receiver_settings.stream).range(0..=MAX_STREAMS - 1));
+
+                if ui.add_enabled(app.connecting.is_none(), Button::new("Connect")).clicked() {
                     println!("Connecting to IP: {}", app.ip_address);
 
-                    // Avvia il ricevitore video
-                    if !app.video_receiver_started {
-                        app.video_receiver_started = true;
+                    // la connessione al caster prosegue in un altro thread
+                    match app.ip_address.parse() {
+                        Ok(address) => app.connecting = Some(ReceiverSession::connect(ctx.clone(), address, app.receiver_settings.clone())),
+                        Err(_) => eprintln!("Indirizzo IP non valido: {}", app.ip_address),
+                    }
+                }
 
-                        let sender_clone = app.sender_channel.clone();
-                        if let Err(e) = start_video_receiver(ctx.clone(), sender_clone) {
-                            eprintln!("Errore nell'avvio della ricezione video: {:?}", e);
+                if let Some(pending) = &app.connecting {
+                    ui.add_space(8.0);
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label(format!("connecting to {}...", pending.address()));
+                    });
+                    match pending.poll() {
+                        None => {}
+                        Some(result) => {
+                            app.connecting = None;
+                            // Avvia il ricevitore video
+                            let frames = Arc::new(FrameQueue::new(1));
+                            let started = match result {
+                                Ok(handshake) => ReceiverSession::start(ctx.clone(), handshake, frames.clone()),
+                                Err(e) => Err(e.into()),
+                            };
+                            match started {
+                                Ok(session) => {
+                                    app.add_receiver(ReceiverTile::new(session, frames));
+                                    app.state = State::Connection; // Passa allo stato di ricezione del video
+                                }
+                                Err(e) => eprintln!("Errore nell'avvio della ricezione video: {:?}", e),
+                            }
                         }
                     }
-
-                    app.state = State::Connection; // Passa allo stato di ricezione del video
                 }
             });
         });
 
-        // Assicurati che il video venga aggiornato
-        update_video_texture(ctx, app);
-
     }
 
-    pub fn video_ui(ctx: &Context, app: &mut MyApp) {
-        update_video_texture(ctx, app); // Aggiorna la texture prima di disegnarla
-
+    //returns where the video was drawn, used to map the pointer onto the frame
+    pub fn video_ui(ctx: &Context, app: &mut MyApp) -> Option<Rect> {
+        let mut video_rect = None;
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.centered_and_justified(|ui| {
                 if let Some(texture) = &app.texture {
@@ -505,13 +1517,15 @@ use egui::TextureHandle;
                     let scale = scale_x.min(scale_y);
                     let scaled_size = (tex_size[0] as f32 * scale, tex_size[1] as f32 * scale);
 
-                    ui.add_sized(scaled_size, Image::from_texture(
+                    let response = ui.add_sized(scaled_size, Image::from_texture(
                         SizedTexture::new(texture.id(), scaled_size)));
+                    video_rect = Some(response.rect);
                 } else {
                     ui.label("Nessun video ricevuto...");
                 }
             });
         });
+        video_rect
     }
 
 
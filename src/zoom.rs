@@ -0,0 +1,138 @@
+use egui::{Color32, Painter, Pos2, Rect, Stroke, TextureId, Vec2};
+
+// Zoom e spostamento del video sul receiver: si cambia solo la porzione della texture disegnata,
+// lo stream e il frame ricevuto restano quelli del caster
+const MAX_ZOOM: f32 = 8.0;
+pub const ZOOM_STEP: f32 = 1.25;
+const MINIMAP_WIDTH: f32 = 160.0;
+const MINIMAP_MARGIN: f32 = 12.0;
+
+/// Where the frame ends up on screen: `frame` is the whole frame, partly outside the panel
+/// when zoomed in, `visible` the part actually drawn and `uv` the same part in texture coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VideoLayout {
+    pub panel: Rect,
+    pub frame: Rect,
+    pub visible: Rect,
+    pub uv: Rect,
+}
+
+impl VideoLayout {
+    pub fn contains(&self, pos: Pos2) -> bool {
+        self.visible.contains(pos)
+    }
+
+    /// Screen position normalised on the frame.
+    pub fn to_frame(&self, pos: Pos2) -> Pos2 {
+        ((pos - self.frame.min) / self.frame.size()).to_pos2()
+    }
+
+    pub fn zoomed(&self) -> bool {
+        self.uv != Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0))
+    }
+
+    /// Thumbnail of the whole frame in the bottom-right corner, shown while zoomed in.
+    pub fn minimap(&self) -> Rect {
+        let size = Vec2::new(MINIMAP_WIDTH, MINIMAP_WIDTH * self.frame.height() / self.frame.width());
+        Rect::from_min_size(self.visible.right_bottom() - size - Vec2::splat(MINIMAP_MARGIN), size)
+    }
+
+    pub fn paint_minimap(&self, painter: &Painter, texture: TextureId) {
+        let map = self.minimap();
+        let full = Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0));
+        painter.rect_filled(map.expand(2.0), 2.0, Color32::from_black_alpha(160));
+        painter.image(texture, map, full, Color32::from_white_alpha(200));
+        let view = Rect::from_min_max(
+            map.min + self.uv.min.to_vec2() * map.size(),
+            map.min + self.uv.max.to_vec2() * map.size(),
+        );
+        painter.rect_stroke(view, 0.0, Stroke::new(1.5, Color32::YELLOW));
+    }
+}
+
+/// Zoom and pan state of the receiver view; `scale` is in points per texel, None = fit to the panel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZoomView {
+    scale: Option<f32>,
+    // centro della parte visibile, in coordinate della texture (0..1)
+    center: Pos2,
+    // zoom dell'ultimo layout rispetto a un texel per pixel fisico
+    percent: f32,
+}
+
+impl Default for ZoomView {
+    fn default() -> Self {
+        ZoomView { scale: None, center: Pos2::new(0.5, 0.5), percent: 100.0 }
+    }
+}
+
+impl ZoomView {
+    pub fn fit(&mut self) {
+        *self = ZoomView::default();
+    }
+
+    /// One texel per physical pixel.
+    pub fn actual_pixels(&mut self, pixels_per_point: f32) {
+        self.scale = Some(1.0 / pixels_per_point);
+    }
+
+    /// Zoom of the last layout relative to one texel per physical pixel.
+    pub fn percent(&self) -> f32 {
+        self.percent
+    }
+
+    /// Computes where the texture goes in the panel, keeping scale and center within bounds.
+    pub fn layout(&mut self, panel: Rect, texture_size: Vec2, pixels_per_point: f32) -> VideoLayout {
+        let fit = (panel.width() / texture_size.x).min(panel.height() / texture_size.y);
+        let actual = 1.0 / pixels_per_point;
+        let scale = self.scale
+            .map(|s| s.clamp(fit.min(actual), fit.max(actual) * MAX_ZOOM))
+            .unwrap_or(fit);
+        let full = texture_size * scale;
+
+        let mut frame_min = Pos2::ZERO;
+        let mut visible = panel;
+        let mut uv = Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0));
+        // gli assi sono indipendenti: centrato se il frame ci sta, altrimenti si mostra la parte intorno a center
+        for axis in 0..2 {
+            let (panel_min, panel_size) = (panel.min[axis], panel.size()[axis]);
+            if full[axis] <= panel_size {
+                frame_min[axis] = panel_min + (panel_size - full[axis]) / 2.0;
+                visible.min[axis] = frame_min[axis];
+                visible.max[axis] = frame_min[axis] + full[axis];
+                self.center[axis] = 0.5;
+            } else {
+                let span = panel_size / full[axis];
+                self.center[axis] = self.center[axis].clamp(span / 2.0, 1.0 - span / 2.0);
+                uv.min[axis] = self.center[axis] - span / 2.0;
+                uv.max[axis] = self.center[axis] + span / 2.0;
+                frame_min[axis] = panel_min - uv.min[axis] * full[axis];
+            }
+        }
+        if self.scale.is_some() {
+            self.scale = Some(scale);
+        }
+        self.percent = scale * pixels_per_point * 100.0;
+        VideoLayout { panel, frame: Rect::from_min_size(frame_min, full), visible, uv }
+    }
+
+    /// Zooms by `factor` keeping the texel under `pointer` where it is.
+    pub fn zoom_at(&mut self, factor: f32, pointer: Pos2, layout: &VideoLayout, texture_size: Vec2) {
+        let uv = layout.to_frame(pointer);
+        let scale = layout.frame.width() / texture_size.x * factor;
+        let full = texture_size * scale;
+        let frame_min = pointer - uv.to_vec2() * full;
+        self.scale = Some(scale);
+        self.center = ((layout.panel.center() - frame_min) / full).to_pos2();
+    }
+
+    /// Moves the view by a screen distance, in points.
+    pub fn pan(&mut self, delta: Vec2, layout: &VideoLayout) {
+        self.center -= delta / layout.frame.size();
+    }
+
+    /// Centers the view on a point of the frame (0..1).
+    pub fn center_on(&mut self, pos: Pos2) {
+        self.center = pos;
+    }
+}